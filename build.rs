@@ -0,0 +1,64 @@
+// Embeds the kernel symbol table used to symbolize backtraces.
+// The table is generated from the `nm` output of a previous build of the kernel, passed through the
+// KERNEL_SYMBOLS environment variable (see `tools/embed-symbols.sh`). It is always padded to the same size, so
+// embedding it does not move any code and the addresses from the previous build remain valid.
+
+use std::{env, fs, path::PathBuf};
+
+/// Size of the embedded symbol table blob. Must be large enough for all function names of the kernel.
+const SYMBOL_TABLE_CAPACITY: usize = 1 << 20;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=KERNEL_SYMBOLS");
+
+    let mut blob = match env::var("KERNEL_SYMBOLS") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            let listing = fs::read_to_string(&path).unwrap_or_else(|e| panic!("reading {} failed: {}", path, e));
+            encode(&listing)
+        }
+        Err(_) => Vec::new(),
+    };
+
+    assert!(blob.len() <= SYMBOL_TABLE_CAPACITY,
+        "symbol table needs {} bytes, but only {} are reserved", blob.len(), SYMBOL_TABLE_CAPACITY);
+    blob.resize(SYMBOL_TABLE_CAPACITY, 0);
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("ksyms.bin"), blob).expect("writing symbol table failed");
+}
+
+/// Encodes `nm -n -C` output into the format read by `src/backtrace.rs`:
+/// magic "KSYM", u32 symbol count, then one (u64 address, u32 name offset, u32 name length) entry per symbol
+/// sorted by address, followed by the names. All integers are little endian.
+fn encode(listing: &str) -> Vec<u8> {
+    let mut symbols: Vec<(u64, &str)> = listing
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let kind = fields.next()?;
+            let name = fields.next()?;
+            matches!(kind, "t" | "T" | "w" | "W").then_some((address, name))
+        })
+        .collect();
+    symbols.sort_by_key(|&(address, _)| address);
+    symbols.dedup_by_key(|&mut (address, _)| address);
+
+    let mut entries = Vec::new();
+    let mut names = Vec::new();
+    for (address, name) in &symbols {
+        entries.extend_from_slice(&address.to_le_bytes());
+        entries.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+
+    let mut blob = Vec::new();
+    blob.extend_from_slice(b"KSYM");
+    blob.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    blob.extend(entries);
+    blob.extend(names);
+    blob
+}
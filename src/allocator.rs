@@ -4,7 +4,7 @@ use x86_64::{
     },
     VirtAddr,
 };
-use spin::Mutex;
+use crate::sync::{IrqMutex, IrqMutexGuard};
 // use bump::BumpAllocator;
 use linked_list::LinkedListAllocator;
 // use fixed_size_block::FixedSizeBlockAllocator;
@@ -37,7 +37,7 @@ pub fn heap_init(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;     // Enable read/write access
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;     // Read/write data only
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?.flush()
         };
@@ -50,21 +50,33 @@ pub fn heap_init(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl
     Ok(())
 }
 
-/// A wrapper around spin::Mutex to permit trait implementations.
+/// Returns where the global allocator was locked, if it is currently held.
+pub(crate) fn lock_holder() -> Option<&'static core::panic::Location<'static>> {
+    ALLOCATOR.holder()
+}
+
+/// A wrapper around IrqMutex to permit trait implementations.
+/// Interrupts are disabled while the allocator is locked, so interrupt handlers can't deadlock on it.
 pub struct Locked<A> {
-    inner: Mutex<A>
+    inner: IrqMutex<A>
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner)
+            inner: IrqMutex::new(inner)
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, A> {
         self.inner.lock()
     }
+
+    /// Returns where the lock was taken, if it is currently held.
+    pub fn holder(&self) -> Option<&'static core::panic::Location<'static>> {
+        self.inner.holder()
+    }
 }
 
 /// Align the given address upwards to alignment. Requires that 'align' is a power of 2.
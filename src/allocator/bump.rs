@@ -40,6 +40,7 @@ impl BumpAllocator {
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+    #[track_caller]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut bump = self.lock();               // Lock the mutex to get mutable access
         
@@ -62,6 +63,7 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         }
     }
 
+    #[track_caller]
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
         let mut bump = self.lock();
         bump.allocations -= 1;
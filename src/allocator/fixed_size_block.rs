@@ -60,6 +60,7 @@ impl FixedSizeBlockAllocator {
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    #[track_caller]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
         match list_index(&layout) {
@@ -83,6 +84,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         }
     }
 
+    #[track_caller]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
         match list_index(&layout) {
@@ -133,6 +133,7 @@ impl LinkedListAllocator {
 }
 
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    #[track_caller]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
@@ -155,6 +156,7 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         }
     }
 
+    #[track_caller]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let (size, _) = LinkedListAllocator::size_align(layout);
         unsafe {
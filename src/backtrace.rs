@@ -0,0 +1,190 @@
+// Stack backtraces
+// The kernel is built with frame pointers (see .cargo/config.toml), so every function starts by pushing the
+// caller's RBP and pointing RBP at it. Following the saved RBP values walks the chain of frames, and the word
+// above each saved RBP is the return address into the caller.
+// Return addresses are resolved with the symbol table embedded by build.rs. The table is only trusted if it
+// lists this module's marker function at its actual address - otherwise it belongs to a different build.
+
+use crate::{memory, serial_println};
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::{structures::idt::InterruptStackFrame, VirtAddr};
+
+/// Upper bound on the number of frames printed, in case the chain is corrupted.
+const MAX_FRAMES: usize = 64;
+
+static SYMBOL_TABLE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin"));
+
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+const MARKER_NAME: &str = "rust_os::backtrace::symbol_table_marker";
+
+const TABLE_UNCHECKED: u8 = 0;
+const TABLE_VALID: u8 = 1;
+const TABLE_INVALID: u8 = 2;
+static TABLE_STATE: AtomicU8 = AtomicU8::new(TABLE_UNCHECKED);
+
+/// A resolved code address.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+/// Returns the frame pointer of the calling function.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// Calls `f` with each return address found by following the frame pointer chain starting at `rbp`.
+pub fn walk(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !is_readable(rbp) || !is_readable(rbp + 8) {
+            break;
+        }
+
+        let (next, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_address == 0 {
+            break;
+        }
+        f(return_address);
+
+        // Stacks grow down, so callers' frames are always at higher addresses
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Prints a backtrace of the calling function to serial.
+#[inline(always)]
+pub fn print() {
+    serial_println!("Backtrace:");
+    print_frames(frame_pointer(), 0);
+}
+
+/// Prints a backtrace of the code interrupted by an exception, starting with the faulting instruction.
+/// Must be called directly from the exception handler, since it follows the frame pointer saved by its prologue.
+#[inline(always)]
+pub fn print_interrupted(stack_frame: &InterruptStackFrame) {
+    let handler_rbp = frame_pointer();
+    let interrupted_rbp = if is_readable(handler_rbp) { unsafe { *(handler_rbp as *const u64) } } else { 0 };
+    print_from(stack_frame.instruction_pointer.as_u64(), interrupted_rbp);
+}
+
+/// Prints a backtrace of code stopped at `rip` with the frame pointer `rbp`, e.g. from a saved register state.
+pub fn print_from(rip: u64, rbp: u64) {
+    serial_println!("Backtrace:");
+    print_address(0, rip);
+    print_frames(rbp, 1);
+}
+
+fn print_frames(rbp: u64, first_index: usize) {
+    let mut index = first_index;
+    walk(rbp, |address| {
+        // Return addresses point after the call - look up the call instruction itself
+        print_address(index, address - 1);
+        index += 1;
+    });
+}
+
+fn print_address(index: usize, address: u64) {
+    match resolve(address) {
+        Some(symbol) => { serial_println!("{:>4}: {:#018x} {}+{:#x}", index, address, symbol.name, symbol.offset); }
+        None => { serial_println!("{:>4}: {:#018x} <unknown>", index, address); }
+    }
+}
+
+/// Returns true if reading 8 bytes at the address won't fault.
+fn is_readable(address: u64) -> bool {
+    let last = address.checked_add(7).and_then(|last| VirtAddr::try_new(last).ok());
+    match (VirtAddr::try_new(address), last) {
+        (Ok(addr), Some(last)) => memory::is_mapped(addr) && memory::is_mapped(last),
+        _ => false,
+    }
+}
+
+/// Resolves an address to the function containing it.
+pub fn resolve(address: u64) -> Option<Symbol> {
+    if !table_is_valid() {
+        return None;
+    }
+
+    // Find the last symbol starting at or before the address
+    let count = symbol_count();
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry(middle).0 <= address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    if low == 0 {
+        return None;
+    }
+
+    let (start, name) = entry(low - 1);
+    Some(Symbol { name, offset: address - start })
+}
+
+/// Marker used to check that the embedded symbol table matches this kernel image.
+#[inline(never)]
+fn symbol_table_marker() {}
+
+fn table_is_valid() -> bool {
+    match TABLE_STATE.load(Ordering::Relaxed) {
+        TABLE_VALID => true,
+        TABLE_INVALID => false,
+        _ => {
+            let valid = &SYMBOL_TABLE[..4] == b"KSYM"
+                && (0..symbol_count()).map(entry).any(|(address, name)| {
+                    name == MARKER_NAME && address == symbol_table_marker as fn() as usize as u64
+                });
+            TABLE_STATE.store(if valid { TABLE_VALID } else { TABLE_INVALID }, Ordering::Relaxed);
+            valid
+        }
+    }
+}
+
+fn read_u32(offset: usize) -> u32 {
+    u32::from_le_bytes(SYMBOL_TABLE[offset..offset + 4].try_into().unwrap())
+}
+
+fn symbol_count() -> usize {
+    read_u32(4) as usize
+}
+
+/// Returns the start address and name of the symbol with the given index.
+fn entry(index: usize) -> (u64, &'static str) {
+    let offset = HEADER_SIZE + index * ENTRY_SIZE;
+    let address = u64::from_le_bytes(SYMBOL_TABLE[offset..offset + 8].try_into().unwrap());
+    let names_start = HEADER_SIZE + symbol_count() * ENTRY_SIZE;
+    let name_offset = names_start + read_u32(offset + 8) as usize;
+    let name_len = read_u32(offset + 12) as usize;
+    let name = core::str::from_utf8(&SYMBOL_TABLE[name_offset..name_offset + name_len]).unwrap_or("<invalid>");
+    (address, name)
+}
+
+#[test_case]
+fn test_walk_finds_caller() {
+    #[inline(never)]
+    fn callee() -> usize {
+        let mut frames = 0;
+        walk(frame_pointer(), |_| frames += 1);
+        frames
+    }
+    assert!(callee() >= 2);
+}
+
+#[test_case]
+fn test_is_readable_rejects_non_canonical_ends() {
+    assert!(!is_readable(0x7fff_ffff_fff9));
+    assert!(!is_readable(0xffff_ffff_ffff_fffc));
+    assert!(!is_readable(0x8000_0000_0000));
+}
@@ -0,0 +1,433 @@
+// Interactive kernel debugger
+// Once enabled, a breakpoint (int3) stops the kernel and opens a monitor on the serial console. It can inspect and
+// modify memory, walk the page tables, show the interrupted registers, stack usage, interrupt statistics, the page
+// table's mappings, the virtual memory areas, physical memory statistics and the executor's tasks, and single-step.
+// The monitor runs inside the exception handler with interrupts disabled, and the watchdog is suspended while it
+// waits for input. Single-stepping sets the trap flag of the interrupted code, so the next instruction raises a
+// debug exception that enters the monitor again.
+// Numbers are hexadecimal, with or without a 0x prefix. Register names can be used wherever an address is expected.
+
+use crate::interrupts::trap::TrapFrame;
+use crate::task::executor::{self, TaskState};
+use crate::{backtrace, interrupts, memory, serial, serial_print, serial_println, watchdog};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// Maximum length of a command line.
+const LINE_CAPACITY: usize = 128;
+
+/// Default and maximum number of bytes shown by a memory dump.
+const DEFAULT_DUMP_LEN: u64 = 0x40;
+const MAX_DUMP_LEN: u64 = 0x400;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STEPPING: AtomicBool = AtomicBool::new(false);
+
+/// Why the kernel stopped in the debugger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint,
+    SingleStep,
+}
+
+/// Makes breakpoints enter the debugger. Without it, breakpoints are only reported and execution continues.
+pub fn init() {
+    ENABLED.store(true, Ordering::Relaxed);
+    serial_println!("Debugger enabled - breakpoints open the monitor on this port");
+}
+
+/// Returns true if breakpoints enter the debugger.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Runs the monitor for the stopped code. Returns false if the debugger did not handle the stop, e.g. because
+/// it is disabled or the debug exception was not caused by single-stepping.
+pub(crate) fn enter(frame: &mut TrapFrame, stop: Stop) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    if stop == Stop::SingleStep {
+        if !STEPPING.load(Ordering::Relaxed) {
+            return false;
+        }
+        // The stepped code holds the serial port - keep stepping until it lets go, printing would deadlock
+        if serial::SERIAL1.is_locked() {
+            return true;
+        }
+    }
+
+    watchdog::suspend();
+    STEPPING.store(false, Ordering::Relaxed);
+    frame.stack_frame.cpu_flags &= !RFlags::TRAP_FLAG.bits();
+
+    let rip = frame.stack_frame.instruction_pointer.as_u64();
+    match stop {
+        Stop::Breakpoint => { serial_print!("\nDEBUGGER: breakpoint at "); }
+        Stop::SingleStep => { serial_print!("DEBUGGER: step to "); }
+    }
+    print_symbolized(rip);
+    serial_println!();
+
+    monitor(frame);
+    watchdog::resume();
+    true
+}
+
+/// Reads and executes commands until the user continues or steps.
+fn monitor(frame: &mut TrapFrame) {
+    let mut line = [0; LINE_CAPACITY];
+    loop {
+        serial_print!("dbg> ");
+        let len = read_line(&mut line);
+        let Ok(line) = core::str::from_utf8(&line[..len]) else {
+            serial_println!("invalid input");
+            continue;
+        };
+
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        let result = match command {
+            "h" | "help" => {
+                print_help();
+                Ok(())
+            }
+            "r" | "regs" => {
+                print_registers(frame);
+                Ok(())
+            }
+            "x" | "dump" => dump_memory(frame, &mut words),
+            "w" | "write" => write_memory(frame, &mut words),
+            "pt" | "walk" => walk_page_tables(frame, &mut words),
+            "st" | "stacks" => {
+                memory::stack::print_usage();
+                Ok(())
+            }
+            "t" | "tasks" => {
+                print_tasks();
+                Ok(())
+            }
+            "irq" | "interrupts" => {
+                interrupts::stats::print();
+                Ok(())
+            }
+            "mp" | "maps" => {
+                memory::inspect::dump_active();
+                Ok(())
+            }
+            "mem" | "memory" => match memory::stats::MemoryStats::try_collect() {
+                Some(stats) => {
+                    stats.print();
+                    Ok(())
+                }
+                None => Err("the frame allocator is locked by the stopped code"),
+            },
+            "vm" | "areas" => match memory::vma::KERNEL_VMAS.try_lock() {
+                Some(vmas) => {
+                    vmas.print_layout();
+                    Ok(())
+                }
+                None => Err("the memory areas are locked by the stopped code"),
+            },
+            "bt" | "backtrace" => {
+                backtrace::print_from(frame.stack_frame.instruction_pointer.as_u64(), frame.rbp);
+                Ok(())
+            }
+            "s" | "step" => {
+                STEPPING.store(true, Ordering::Relaxed);
+                frame.stack_frame.cpu_flags |= RFlags::TRAP_FLAG.bits();
+                return;
+            }
+            "c" | "continue" => return,
+            _ => Err("unknown command, try 'help'"),
+        };
+        if let Err(message) = result {
+            serial_println!("error: {}", message);
+        }
+    }
+}
+
+fn print_help() {
+    serial_println!("Commands (numbers are hex, register names can be used as addresses):");
+    serial_println!("  regs | r                  show the registers of the stopped code");
+    serial_println!("  dump | x <addr> [len]     dump memory");
+    serial_println!("  write | w <addr> <byte>.. write bytes to memory");
+    serial_println!("  walk | pt <addr>          walk the page tables for an address");
+    serial_println!("  stacks | st               show the maximum usage of the kernel stacks");
+    serial_println!("  tasks | t                 list the executor's tasks");
+    serial_println!("  interrupts | irq          show the per-vector interrupt statistics");
+    serial_println!("  maps | mp                 list everything the active page table maps");
+    serial_println!("  memory | mem              show physical memory statistics");
+    serial_println!("  areas | vm                show the kernel's virtual memory areas");
+    serial_println!("  backtrace | bt            show the call stack of the stopped code");
+    serial_println!("  step | s                  execute one instruction");
+    serial_println!("  continue | c              resume execution");
+}
+
+/// Reads a line from serial into `buffer`, echoing it back. Returns the length of the line.
+fn read_line(buffer: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        match serial::read_byte() {
+            b'\r' | b'\n' => {
+                serial_println!();
+                return len;
+            }
+            // Backspace and delete
+            8 | 0x7f => {
+                if len > 0 {
+                    len -= 1;
+                    serial_print!("\x08 \x08");
+                }
+            }
+            byte @ 0x20..=0x7e if len < buffer.len() => {
+                buffer[len] = byte;
+                len += 1;
+                serial_print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn print_symbolized(address: u64) {
+    match backtrace::resolve(address) {
+        Some(symbol) => { serial_print!("{:#018x} {}+{:#x}", address, symbol.name, symbol.offset); }
+        None => { serial_print!("{:#018x}", address); }
+    }
+}
+
+fn print_registers(frame: &TrapFrame) {
+    use x86_64::registers::control::{Cr2, Cr3};
+
+    for (index, (name, value)) in frame.registers().iter().enumerate() {
+        serial_print!("{:>3} {:#018x}{}", name, value, if index % 4 == 3 { "\n" } else { "  " });
+    }
+    serial_print!("rip ");
+    print_symbolized(frame.stack_frame.instruction_pointer.as_u64());
+    serial_println!();
+    serial_println!("rflags {:#x} {:?}", frame.stack_frame.cpu_flags, RFlags::from_bits_truncate(frame.stack_frame.cpu_flags));
+    serial_println!("cs {:#x}  ss {:#x}", frame.stack_frame.code_segment, frame.stack_frame.stack_segment);
+    serial_println!("cr2 {:#x}  cr3 {:#x}", Cr2::read_raw(), Cr3::read().0.start_address().as_u64());
+}
+
+/// Parses a hexadecimal number or the name of a register of the stopped code.
+fn parse_value(frame: &TrapFrame, word: Option<&str>) -> Result<u64, &'static str> {
+    let word = word.ok_or("missing argument")?;
+    if word == "rip" {
+        return Ok(frame.stack_frame.instruction_pointer.as_u64());
+    }
+    if let Some(&(_, value)) = frame.registers().iter().find(|(name, _)| *name == word) {
+        return Ok(value);
+    }
+    let digits = word.strip_prefix("0x").unwrap_or(word);
+    u64::from_str_radix(digits, 16).map_err(|_| "invalid number")
+}
+
+fn parse_address(frame: &TrapFrame, word: Option<&str>) -> Result<VirtAddr, &'static str> {
+    VirtAddr::try_new(parse_value(frame, word)?).map_err(|_| "address is not canonical")
+}
+
+/// Returns the address `offset` bytes after `start`, or `None` if it isn't canonical.
+fn offset_address(start: VirtAddr, offset: u64) -> Option<VirtAddr> {
+    start.as_u64().checked_add(offset).and_then(|address| VirtAddr::try_new(address).ok())
+}
+
+/// Returns true if the address is mapped with all levels of the page tables allowing writes.
+fn is_writable(address: VirtAddr) -> bool {
+    let mut writable = true;
+    let mut mapped = false;
+    let walked = memory::walk_page_tables(address, |entry| {
+        writable &= entry.flags.contains(PageTableFlags::WRITABLE);
+        mapped = entry.flags.contains(PageTableFlags::PRESENT)
+            && (entry.level == 1 || entry.flags.contains(PageTableFlags::HUGE_PAGE));
+    });
+    walked && mapped && writable
+}
+
+fn dump_memory<'a>(frame: &TrapFrame, words: &mut impl Iterator<Item = &'a str>) -> Result<(), &'static str> {
+    let start = parse_address(frame, words.next())?;
+    let len = match words.next() {
+        Some(word) => parse_value(frame, Some(word))?.min(MAX_DUMP_LEN),
+        None => DEFAULT_DUMP_LEN,
+    };
+
+    for row in (0..len).step_by(16) {
+        // The dump stops where the canonical addresses end, bytes past it in the last row show as `??`
+        let Some(row_address) = offset_address(start, row) else {
+            break;
+        };
+        let mut bytes = [None; 16];
+        for (column, byte) in bytes.iter_mut().enumerate().take((len - row).min(16) as usize) {
+            if let Some(address) = offset_address(row_address, column as u64)
+                && memory::is_mapped(address)
+            {
+                *byte = Some(unsafe { address.as_ptr::<u8>().read_volatile() });
+            }
+        }
+
+        serial_print!("{:#018x}:", row_address.as_u64());
+        for byte in bytes {
+            match byte {
+                Some(byte) => { serial_print!(" {:02x}", byte); }
+                None => { serial_print!(" ??"); }
+            }
+        }
+        serial_print!("  ");
+        for byte in bytes {
+            let c = match byte {
+                Some(byte) if byte.is_ascii_graphic() || byte == b' ' => byte as char,
+                Some(_) => '.',
+                None => '?',
+            };
+            serial_print!("{}", c);
+        }
+        serial_println!();
+    }
+    Ok(())
+}
+
+fn write_memory<'a>(frame: &TrapFrame, words: &mut impl Iterator<Item = &'a str>) -> Result<(), &'static str> {
+    let start = parse_address(frame, words.next())?;
+
+    // Parse all bytes before writing any of them
+    let mut bytes = [0u8; LINE_CAPACITY / 2];
+    let mut count = 0;
+    for word in words {
+        let value = parse_value(frame, Some(word))?;
+        let byte = u8::try_from(value).map_err(|_| "values must be bytes")?;
+        *bytes.get_mut(count).ok_or("too many bytes")? = byte;
+        count += 1;
+    }
+    if count == 0 {
+        return Err("missing bytes to write");
+    }
+    if offset_address(start, count as u64 - 1).is_none() {
+        return Err("address range is not canonical");
+    }
+    if !(0..count as u64).all(|offset| is_writable(start + offset)) {
+        return Err("address range is not writable");
+    }
+
+    for (offset, byte) in bytes[..count].iter().enumerate() {
+        unsafe { (start + offset as u64).as_mut_ptr::<u8>().write_volatile(*byte) };
+    }
+    serial_println!("wrote {} bytes", count);
+    Ok(())
+}
+
+fn walk_page_tables<'a>(frame: &TrapFrame, words: &mut impl Iterator<Item = &'a str>) -> Result<(), &'static str> {
+    let address = parse_address(frame, words.next())?;
+
+    let mut physical = None;
+    let walked = memory::walk_page_tables(address, |entry| {
+        serial_println!("P{} [{:3}] {:#014x} {:?}", entry.level, entry.index, entry.addr.as_u64(), entry.flags);
+        if !entry.flags.contains(PageTableFlags::PRESENT) {
+            return;
+        }
+        // Offset of the address within the page mapped by this entry
+        let page_offset = match entry.level {
+            1 => address.as_u64() & 0xfff,
+            2 if entry.flags.contains(PageTableFlags::HUGE_PAGE) => address.as_u64() & 0x1f_ffff,
+            3 if entry.flags.contains(PageTableFlags::HUGE_PAGE) => address.as_u64() & 0x3fff_ffff,
+            _ => return,
+        };
+        physical = Some(entry.addr.as_u64() + page_offset);
+    });
+
+    if !walked {
+        return Err("page tables are not accessible yet");
+    }
+    match physical {
+        Some(physical) => { serial_println!("{:#x} -> physical {:#x}", address.as_u64(), physical); }
+        None => { serial_println!("{:#x} is not mapped", address.as_u64()); }
+    }
+    Ok(())
+}
+
+fn print_tasks() {
+    let mut count = 0;
+    let listed = executor::for_each_task(|task| {
+        let state = match task.state {
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Waiting => "waiting",
+        };
+        serial_println!("task {:>4}  {:<8} {} polls", task.id, state, task.polls);
+        count += 1;
+    });
+
+    if !listed {
+        serial_println!("task list is locked by the stopped code");
+    } else if count == 0 {
+        serial_println!("no tasks");
+    }
+}
+
+/// Returns a trap frame whose registers hold their own index, with rip and rsp set to recognizable values.
+#[cfg(test)]
+fn test_frame() -> TrapFrame {
+    use x86_64::structures::idt::InterruptStackFrameValue;
+    TrapFrame {
+        r15: 15, r14: 14, r13: 13, r12: 12, r11: 11, r10: 10, r9: 9, r8: 8,
+        rbp: 6, rdi: 5, rsi: 4, rdx: 3, rcx: 2, rbx: 1, rax: 0,
+        stack_frame: InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::new(0x20_1000),
+            code_segment: 8,
+            cpu_flags: 0,
+            stack_pointer: VirtAddr::new(0x7000),
+            stack_segment: 0,
+        },
+    }
+}
+
+#[test_case]
+fn test_parse_numbers() {
+    let frame = test_frame();
+    assert_eq!(parse_value(&frame, Some("ff")), Ok(0xff));
+    assert_eq!(parse_value(&frame, Some("0x1234")), Ok(0x1234));
+    assert_eq!(parse_value(&frame, Some("ffffffffffffffff")), Ok(u64::MAX));
+    assert_eq!(parse_value(&frame, Some("10000000000000000")), Err("invalid number"));
+    assert_eq!(parse_value(&frame, Some("0xg")), Err("invalid number"));
+    assert_eq!(parse_value(&frame, None), Err("missing argument"));
+}
+
+#[test_case]
+fn test_parse_registers() {
+    let frame = test_frame();
+    assert_eq!(parse_value(&frame, Some("rax")), Ok(0));
+    assert_eq!(parse_value(&frame, Some("r13")), Ok(13));
+    assert_eq!(parse_value(&frame, Some("rip")), Ok(0x20_1000));
+    assert_eq!(parse_value(&frame, Some("rsp")), Ok(0x7000));
+    assert_eq!(parse_address(&frame, Some("rsp")), Ok(VirtAddr::new(0x7000)));
+    assert_eq!(parse_address(&frame, Some("0x800000000000")), Err("address is not canonical"));
+}
+
+#[test_case]
+fn test_write_memory_rejects_bad_bytes() {
+    let mut buffer = [0u8; 4];
+    let mut frame = test_frame();
+    frame.rdi = buffer.as_mut_ptr() as u64;
+
+    assert_eq!(write_memory(&frame, &mut ["rdi", "1", "100"].into_iter()), Err("values must be bytes"));
+    assert_eq!(write_memory(&frame, &mut ["rdi"].into_iter()), Err("missing bytes to write"));
+    assert_eq!(unsafe { core::ptr::read_volatile(&buffer) }, [0; 4]);
+
+    assert_eq!(write_memory(&frame, &mut ["rdi", "1", "0xff", "rcx"].into_iter()), Ok(()));
+    assert_eq!(unsafe { core::ptr::read_volatile(&buffer) }, [1, 0xff, 2, 0]);
+}
+
+#[test_case]
+fn test_ranges_past_the_canonical_end() {
+    let frame = test_frame();
+    assert_eq!(write_memory(&frame, &mut ["0x7fffffffffff", "1", "2"].into_iter()),
+        Err("address range is not canonical"));
+    assert_eq!(write_memory(&frame, &mut ["0xffffffffffffffff", "1", "2"].into_iter()),
+        Err("address range is not canonical"));
+    assert_eq!(dump_memory(&frame, &mut ["0x7ffffffffff0", "0x40"].into_iter()), Ok(()));
+    assert_eq!(dump_memory(&frame, &mut ["0xfffffffffffffff0", "0x40"].into_iter()), Ok(()));
+}
@@ -1,55 +1,143 @@
+use core::cell::UnsafeCell;
+use crate::memory::stack::{self, StackError};
 use x86_64::VirtAddr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use lazy_static::lazy_static;
 
+// Interrupt stack table indexes - exceptions that must not run on a possibly corrupted stack get their own
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+#[cfg(feature = "page-fault-ist")]
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
+
+/// IST indexes that have a stack, with the stack names used for usage tracking
+const IST_INDEXES: &[(u16, &str)] = &[
+    (DOUBLE_FAULT_IST_INDEX, "double fault"),
+    (NMI_IST_INDEX, "nmi"),
+    (MACHINE_CHECK_IST_INDEX, "machine check"),
+    #[cfg(feature = "page-fault-ist")]
+    (PAGE_FAULT_IST_INDEX, "page fault"),
+];
+
+/// Size of each IST stack in pages
+pub const IST_STACK_PAGES: u64 = 5;
+
+/// Size of the boot context's privilege stack (RSP0) in pages
+const PRIVILEGE_STACK_PAGES: u64 = 5;
+const IST_STACK_SIZE: usize = 4096 * IST_STACK_PAGES as usize;
+
+/// Task State Segment that can be updated after it has been loaded, e.g. to move an IST stack or switch RSP0
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}         // Only modified with interrupts disabled
 
 lazy_static! {
-    /// Custom Task State Segment to hold a separate stack for use in the double fault
-    static ref TSS: TaskStateSegment = {
+    /// Custom Task State Segment to hold separate stacks for the exceptions in `IST_INDEXES`
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
 
-        // Create a new stack
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        // Boot stacks, used until `init_stacks` replaces them with guard-paged ones - there is no page
+        // allocator yet when the GDT is loaded
+        static mut STACKS: [[u8; IST_STACK_SIZE]; IST_INDEXES.len()] = [[0; IST_STACK_SIZE]; IST_INDEXES.len()];
+        for (stack, &(index, _)) in IST_INDEXES.iter().enumerate() {
+            let stack_start = VirtAddr::from_ptr(unsafe { &raw const STACKS[stack] });
+            let stack_end = stack_start + IST_STACK_SIZE;
 
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
+            tss.interrupt_stack_table[usize::from(index)] = stack_end;
+        }
 
-            stack_end
-        };
-
-        tss
+        Tss(UnsafeCell::new(tss))
     };
 }
 
 lazy_static! {
     /// Global Descriptor Table to load our custom TSS
+    /// The segment order is fixed by SYSCALL/SYSRET: the kernel data segment must follow the kernel code segment,
+    /// and the user code segment must follow the user data segment
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
         
-        (gdt, Selectors {code_selector, tss_selector})
+        (gdt, Selectors {code_selector, data_selector, user_code_selector, user_data_selector, tss_selector})
     };
 }
 
-/// Variables needed for GDT initialization
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// Segment selectors of the GDT
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
 }
 
 /// Initialize the GDT
 pub fn init() {
     use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, SS, Segment};
 
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);       // Reload the code segment register
+        SS::set_reg(GDT.1.data_selector);       // SYSCALL loads the same stack segment, keep them consistent
         load_tss(GDT.1.tss_selector);           // Load our custom TSS
     }
+}
+
+/// Replaces the boot IST stacks with guard-paged stacks. Requires the GDT to be loaded.
+pub fn init_stacks(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), StackError> {
+    for &(index, name) in IST_INDEXES {
+        let stack = stack::allocate_stack(name, IST_STACK_PAGES, mapper, frame_allocator)?;
+        unsafe { set_ist_stack(index, stack.end()) };
+    }
+
+    let privilege_stack = stack::allocate_stack("privilege", PRIVILEGE_STACK_PAGES, mapper, frame_allocator)?;
+    unsafe { set_privilege_stack(privilege_stack.end()) };
+    Ok(())
+}
+
+/// Sets the stack the CPU switches to for interrupts using the given IST index.
+///
+/// # Safety
+/// `stack_end` must be the top of a mapped stack that is large enough for the interrupt handlers using the index.
+pub unsafe fn set_ist_stack(index: u16, stack_end: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*TSS.0.get()).interrupt_stack_table[usize::from(index)] = stack_end;
+    });
+}
+
+/// Returns the stack the CPU switches to for interrupts using the given IST index.
+pub fn ist_stack(index: u16) -> VirtAddr {
+    unsafe { (*TSS.0.get()).interrupt_stack_table[usize::from(index)] }
+}
+
+/// Sets the stack the CPU switches to when an interrupt arrives in user mode (RSP0).
+/// Each user mode context needs its own, so it has to be updated whenever the running context changes.
+///
+/// # Safety
+/// `stack_end` must be the top of a mapped kernel stack that isn't in use.
+pub unsafe fn set_privilege_stack(stack_end: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*TSS.0.get()).privilege_stack_table[0] = stack_end;
+    });
+}
+
+/// Returns the stack the CPU switches to when an interrupt arrives in user mode.
+pub fn privilege_stack() -> VirtAddr {
+    unsafe { (*TSS.0.get()).privilege_stack_table[0] }
+}
+
+/// Returns the segment selectors of the GDT.
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
 }
\ No newline at end of file
@@ -1,13 +1,30 @@
 use crate::{print, println};
-use crate::{gdt, idle_loop};
+use crate::{debugger, gdt, idle_loop, sync::IrqMutex};
+use trap::{trap_entry, TrapFrame};
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
 use pic8259::ChainedPics;
 use lazy_static::lazy_static;
-use spin;
+
+pub mod deferred;
+pub mod irq;
+pub mod lapic;
+pub mod pic;
+pub mod stats;
+pub mod trap;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+// Exception vector numbers
+pub const DEBUG_VECTOR: u8 = 1;
+pub const BREAKPOINT_VECTOR: u8 = 3;
+pub const NMI_VECTOR: u8 = 2;
+pub const DOUBLE_FAULT_VECTOR: u8 = 8;
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+pub const MACHINE_CHECK_VECTOR: u8 = 18;
+
 /// Chained Programmable Interrupt Controllers. Example configuration:
 ///                      ____________                          ____________
 /// Real Time Clock --> |            |   Timer -------------> |            |
@@ -18,27 +35,8 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 /// Co-Processor -----> |            |   Parallel Port 2/3 -> |            |
 /// Primary ATA ------> |            |   Floppy disk -------> |            |
 /// Secondary ATA ----> |____________|   Parallel Port 1----> |____________|
-pub static PICS: spin::Mutex<ChainedPics> = 
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });      // Unsafe because incorrect offsets can cause undefined behavior
-
-/// Enum representing indexes for interrupt variants
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard
-}
-
-/// InterruptIndex helper functions
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
-}
+pub static PICS: IrqMutex<ChainedPics> = 
+    IrqMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });      // Unsafe because incorrect offsets can cause undefined behavior
 
 lazy_static! {
     /// Static IDT instance - load expects an IDT with 'static lifetime
@@ -46,14 +44,36 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
     
         // Set exception handlers
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        unsafe {
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+        }
+        unsafe {
+            // Enter through register-saving stubs so the debugger can inspect and modify the interrupted state
+            idt.debug.set_handler_addr(VirtAddr::new(debug_entry as extern "C" fn() as usize as u64));
+            idt.breakpoint.set_handler_addr(VirtAddr::new(breakpoint_entry as extern "C" fn() as usize as u64));
+        }
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX)
         };
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        unsafe {
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        #[cfg(not(feature = "page-fault-ist"))]
         idt.page_fault.set_handler_fn(page_fault_handler);
+        // A fault while handling a page fault restarts the IST stack from its top and clobbers the outer
+        // handler's frame, so this is opt-in
+        #[cfg(feature = "page-fault-ist")]
+        unsafe {
+            idt.page_fault.set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
+
+        // Set hardware interrupt handlers - drivers register with the IRQ dispatcher at runtime
+        irq::install_stubs(&mut idt);
+        idt[usize::from(lapic::SPURIOUS_VECTOR)].set_handler_fn(lapic_spurious_handler);
 
         idt
     };
@@ -61,13 +81,39 @@ lazy_static! {
 
 pub fn idt_init() {
     IDT.load();
+    irq::register_irq(irq::TIMER, timer_interrupt_handler).expect("registering timer handler failed");
+}
+
+/// Non-maskable interrupt handler
+/// NMIs are raised by the watchdog's performance counter and by hardware errors
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame)
+{
+    let _measurement = stats::measure(NMI_VECTOR);
+    crate::watchdog::handle_nmi(&stack_frame);
+}
+
+trap_entry!(debug_entry => debug_handler);
+trap_entry!(breakpoint_entry => breakpoint_handler);
+
+/// Debug exception handler
+/// Raised after each instruction while the trap flag is set, which the debugger uses for single-stepping
+extern "C" fn debug_handler(frame: &mut TrapFrame)
+{
+    let _measurement = stats::measure(DEBUG_VECTOR);
+    if !debugger::enter(frame, debugger::Stop::SingleStep) {
+        println!("EXCEPTION: DEBUG\n{:#?}", frame.stack_frame);
+        frame.stack_frame.cpu_flags &= !RFlags::TRAP_FLAG.bits();     // Don't trap again on the next instruction
+    }
 }
 
 /// Breakpoint exception handler
-/// extern "x86-interrupt" specifies the calling convention for interrupt handlers
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame)
+/// Opens the debugger if it is enabled, otherwise reports the breakpoint and continues
+extern "C" fn breakpoint_handler(frame: &mut TrapFrame)
 {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    let _measurement = stats::measure(BREAKPOINT_VECTOR);
+    if !debugger::enter(frame, debugger::Stop::Breakpoint) {
+        println!("EXCEPTION: BREAKPOINT\n{:#?}", frame.stack_frame);
+    }
 }
 
 /// Double fault exception handler
@@ -75,46 +121,48 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame)
 /// Handler is diverging - x86_64 architecture does not permit a return from double fault
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+    stats::count(DOUBLE_FAULT_VECTOR);
+    crate::backtrace::print_interrupted(&stack_frame);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+/// Machine check exception handler
+/// Raised for uncorrectable hardware errors - the interrupted state can't be trusted, so it is not resumed
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> !
+{
+    stats::count(MACHINE_CHECK_VECTOR);
+    crate::backtrace::print_interrupted(&stack_frame);
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
+
 /// Page fault exception handler
 /// Occurs when a page fault happens (e.g. accessing a page that is not mapped to physical memory)
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
 {
     use x86_64::registers::control::Cr2;
 
+    stats::count(PAGE_FAULT_VECTOR);
+    if crate::memory::cow::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed address: {:?}", Cr2::read());   // CR2 register contains the accessed virtual address that caused the fault
     println!("Error code: {:?}", error_code);
     println!("{:#?}", stack_frame);
+    crate::backtrace::print_interrupted(&stack_frame);
     idle_loop();
 }
 
 /// Timer interrupt handler function
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame)
+fn timer_interrupt_handler(_stack_frame: &InterruptStackFrame)
 {
     print!(".");
-
-    // Figure out whether primary/secondary PIC sent the interrupt and send an EOI signal to the proper controller
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
 }
 
-/// Keyboard interrupt handler function
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame)
+/// Local APIC spurious interrupt handler
+/// Spurious interrupts must not be acknowledged with an EOI
+extern "x86-interrupt" fn lapic_spurious_handler(_stack_frame: InterruptStackFrame)
 {
-    use x86_64::instructions::port::Port;
-
-    let mut port = Port::new(0x60);     // PS/2 controller - I/O port 0x60
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
-
-    // Figure out whether primary/secondary PIC sent the interrupt and send an EOI signal to the proper controller
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
 }
 
 #[test_case]
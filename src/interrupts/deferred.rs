@@ -0,0 +1,118 @@
+// Deferred interrupt work ("bottom halves")
+// Interrupt handlers run with interrupts disabled and should only do what can't wait. Everything else can be
+// queued as a work item and runs later with interrupts enabled: right after the IRQ dispatcher has sent the EOI,
+// or from the executor loop before any task is polled.
+// Work items are plain function pointers with a word-sized argument, so queueing them never allocates.
+
+use crate::time;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crossbeam_queue::ArrayQueue;
+
+/// Maximum number of pending work items.
+const QUEUE_CAPACITY: usize = 256;
+
+/// A function to run later, together with its argument and the time it was queued at.
+#[derive(Debug, Clone, Copy)]
+struct WorkItem {
+    func: fn(usize),
+    arg: usize,
+    queued_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferError {
+    /// `init` has not been called yet
+    Uninitialized,
+    /// Too many work items are pending
+    QueueFull,
+}
+
+/// Latency statistics of deferred work, measured from queueing to the start of execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeferredStats {
+    pub executed: u64,
+    pub dropped: u64,
+    pub average_latency_ns: u64,
+    pub max_latency_ns: u64,
+}
+
+static QUEUE: OnceCell<ArrayQueue<WorkItem>> = OnceCell::uninit();
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+static EXECUTED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static TOTAL_LATENCY_NS: AtomicU64 = AtomicU64::new(0);
+static MAX_LATENCY_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Allocate the work queue. Must be called once the heap is initialized.
+pub fn init() {
+    QUEUE.try_init_once(|| ArrayQueue::new(QUEUE_CAPACITY))
+        .expect("deferred::init should only be called once!");
+}
+
+/// Queues `func(arg)` to run with interrupts enabled. Safe to call from interrupt handlers - never blocks or allocates.
+pub fn schedule(func: fn(usize), arg: usize) -> Result<(), DeferError> {
+    let result = match QUEUE.try_get() {
+        Ok(queue) => queue
+            .push(WorkItem { func, arg, queued_at: time::now() })
+            .map_err(|_| DeferError::QueueFull),
+        Err(_) => Err(DeferError::Uninitialized),
+    };
+
+    if result.is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// Returns true if work items are waiting to run.
+pub fn has_pending() -> bool {
+    QUEUE.try_get().map(|queue| !queue.is_empty()).unwrap_or(false)
+}
+
+/// Runs all pending work items. Must be called with interrupts enabled. Returns immediately if work items
+/// are already being run further up the stack, so an interrupt arriving during a work item doesn't nest.
+pub fn run_pending() {
+    let Ok(queue) = QUEUE.try_get() else {
+        return;
+    };
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    while let Some(item) = queue.pop() {
+        let latency = time::now().saturating_sub(item.queued_at);
+        EXECUTED.fetch_add(1, Ordering::Relaxed);
+        TOTAL_LATENCY_NS.fetch_add(latency, Ordering::Relaxed);
+        MAX_LATENCY_NS.fetch_max(latency, Ordering::Relaxed);
+
+        (item.func)(item.arg);
+    }
+
+    RUNNING.store(false, Ordering::Release);
+}
+
+/// Runs pending work at the end of an interrupt handler, after the EOI has been sent.
+pub(super) fn run_pending_from_irq() {
+    use x86_64::instructions::interrupts;
+
+    if !has_pending() || RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    interrupts::enable();
+    run_pending();
+    interrupts::disable();      // The handler must return with interrupts disabled, iretq restores them
+}
+
+/// Returns the deferred work statistics.
+pub fn stats() -> DeferredStats {
+    let executed = EXECUTED.load(Ordering::Relaxed);
+    DeferredStats {
+        executed,
+        dropped: DROPPED.load(Ordering::Relaxed),
+        average_latency_ns: TOTAL_LATENCY_NS.load(Ordering::Relaxed).checked_div(executed).unwrap_or(0),
+        max_latency_ns: MAX_LATENCY_NS.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,178 @@
+// Dynamic IRQ handler registration
+// Every PIC line gets a generic interrupt stub in the IDT. The stub dispatches to the handlers registered for its
+// line at runtime and sends the end of interrupt signal afterwards, so drivers never have to touch the IDT.
+// Lines may be shared: all handlers registered on a line are called for every interrupt on it, and each
+// handler is expected to check whether its device actually raised the interrupt. A line is unmasked while at
+// least one handler is registered on it.
+// Handler slots are reused, so every slot counts how often it has been handed out. Ids carry that generation, which
+// keeps a stale id from unregistering a later handler in the same slot.
+
+use super::{deferred, pic, stats, PICS, PIC_1_OFFSET};
+use crate::sync::IrqMutex;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+/// Number of IRQ lines provided by the chained PICs.
+pub const IRQ_LINES: usize = 16;
+
+/// Maximum number of handlers that can share a single line.
+const MAX_SHARED_HANDLERS: usize = 4;
+
+// Standard ISA IRQ line assignments
+pub const TIMER: u8 = 0;
+pub const KEYBOARD: u8 = 1;
+pub const CASCADE: u8 = 2;
+pub const COM2: u8 = 3;
+pub const COM1: u8 = 4;
+pub const LPT2: u8 = 5;
+pub const FLOPPY: u8 = 6;
+pub const LPT1: u8 = 7;
+pub const RTC: u8 = 8;
+pub const MOUSE: u8 = 12;
+pub const FPU: u8 = 13;
+pub const PRIMARY_ATA: u8 = 14;
+pub const SECONDARY_ATA: u8 = 15;
+
+/// Interrupt handler called for every interrupt on the line it is registered to, with the stack frame of the
+/// interrupted code. Runs with interrupts disabled - must not block or allocate.
+pub type IrqHandler = fn(&InterruptStackFrame);
+
+/// Token identifying a registered handler, used to unregister it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandlerId {
+    line: u8,
+    slot: usize,
+    generation: u32,
+}
+
+impl IrqHandlerId {
+    pub fn line(&self) -> u8 {
+        self.line
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The line number is not a valid PIC line
+    InvalidLine(u8),
+    /// All handler slots of the line are taken
+    LineFull(u8),
+    /// The handler was not registered (or was already unregistered)
+    NotRegistered,
+}
+
+/// A handler slot of a line.
+#[derive(Clone, Copy)]
+struct Slot {
+    handler: Option<IrqHandler>,
+    /// Number of times the slot has been handed out
+    generation: u32,
+}
+
+static HANDLERS: IrqMutex<[[Slot; MAX_SHARED_HANDLERS]; IRQ_LINES]> =
+    IrqMutex::new([[Slot { handler: None, generation: 0 }; MAX_SHARED_HANDLERS]; IRQ_LINES]);
+
+/// Registers a handler for the given IRQ line. Returns an id that can be passed to `unregister_irq`.
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<IrqHandlerId, IrqError> {
+    let slots_index = usize::from(line);
+    if slots_index >= IRQ_LINES {
+        return Err(IrqError::InvalidLine(line));
+    }
+
+    let mut handlers = HANDLERS.lock();
+    let slot = handlers[slots_index]
+        .iter()
+        .position(|slot| slot.handler.is_none())
+        .ok_or(IrqError::LineFull(line))?;
+    let entry = &mut handlers[slots_index][slot];
+    entry.handler = Some(handler);
+    entry.generation = entry.generation.wrapping_add(1);
+    let generation = entry.generation;
+    pic::unmask_irq(line);
+    Ok(IrqHandlerId { line, slot, generation })
+}
+
+/// Removes a previously registered handler.
+pub fn unregister_irq(id: IrqHandlerId) -> Result<(), IrqError> {
+    let mut handlers = HANDLERS.lock();
+    let slots = &mut handlers[usize::from(id.line)];
+    let slot = &mut slots[id.slot];
+    if slot.generation != id.generation {
+        return Err(IrqError::NotRegistered);
+    }
+    slot.handler.take().ok_or(IrqError::NotRegistered)?;
+
+    // Nobody is listening anymore - stop the line from interrupting
+    if slots.iter().all(|slot| slot.handler.is_none()) {
+        pic::mask_irq(id.line);
+    }
+    Ok(())
+}
+
+/// Calls all handlers registered for the line, signals the end of interrupt and runs deferred work.
+fn dispatch(line: u8, stack_frame: &InterruptStackFrame) {
+    if pic::is_spurious(line) {
+        return;
+    }
+
+    {
+        let _measurement = stats::measure(PIC_1_OFFSET + line);
+
+        // Copy the handlers out so they are free to (un)register handlers themselves
+        let handlers = HANDLERS.lock()[usize::from(line)];
+        for handler in handlers.iter().filter_map(|slot| slot.handler) {
+            handler(stack_frame);
+        }
+
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + line);
+        }
+    }
+
+    deferred::run_pending_from_irq();
+}
+
+/// Generates one interrupt stub per IRQ line that forwards to `dispatch`.
+macro_rules! irq_stubs {
+    ($($name:ident => $line:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+                dispatch($line, &stack_frame);
+            }
+        )*
+
+        const STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] = [$($name),*];
+    };
+}
+
+irq_stubs! {
+    irq0 => 0, irq1 => 1, irq2 => 2, irq3 => 3,
+    irq4 => 4, irq5 => 5, irq6 => 6, irq7 => 7,
+    irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11,
+    irq12 => 12, irq13 => 13, irq14 => 14, irq15 => 15,
+}
+
+/// Installs the dispatch stubs for all IRQ lines in the IDT.
+pub(super) fn install_stubs(idt: &mut InterruptDescriptorTable) {
+    for (line, stub) in STUBS.iter().enumerate() {
+        idt[usize::from(PIC_1_OFFSET) + line].set_handler_fn(*stub);
+    }
+}
+
+#[test_case]
+fn test_register_and_unregister() {
+    fn handler(_stack_frame: &InterruptStackFrame) {}
+
+    let first = register_irq(LPT2, handler).expect("registering handler failed");
+    let second = register_irq(LPT2, handler).expect("registering shared handler failed");
+    assert_ne!(first, second);
+
+    assert_eq!(unregister_irq(first), Ok(()));
+    assert_eq!(unregister_irq(first), Err(IrqError::NotRegistered));
+    assert_eq!(unregister_irq(second), Ok(()));
+
+    // A stale id doesn't remove the handler that reuses its slot
+    let third = register_irq(LPT2, handler).expect("registering handler failed");
+    assert_eq!(unregister_irq(first), Err(IrqError::NotRegistered));
+    assert_eq!(unregister_irq(third), Ok(()));
+    assert_eq!(register_irq(IRQ_LINES as u8, handler), Err(IrqError::InvalidLine(IRQ_LINES as u8)));
+}
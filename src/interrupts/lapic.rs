@@ -0,0 +1,65 @@
+// Local APIC register access
+// Hardware interrupts are still delivered through the 8259 PICs (the local APIC passes them through in virtual
+// wire mode), but the local APIC is needed for its local vector table entries, e.g. to turn performance counter
+// overflows into NMIs. Its registers are memory mapped at the physical address in the IA32_APIC_BASE MSR; `init`
+// maps them uncached.
+
+use crate::memory::mmio::{self, CachePolicy, MmioRegion};
+use conquer_once::spin::OnceCell;
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Size of the register page.
+const REGISTERS_SIZE: u64 = 0x1000;
+
+static REGISTERS: OnceCell<MmioRegion> = OnceCell::uninit();
+
+// Register offsets
+pub const REG_SPURIOUS_VECTOR: usize = 0xF0;
+pub const REG_LVT_PERFORMANCE_COUNTER: usize = 0x340;
+
+const SPURIOUS_VECTOR_ENABLE: u32 = 1 << 8;
+/// Vector of spurious local APIC interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// LVT delivery mode for non-maskable interrupts.
+pub const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// Returns the physical base address of the local APIC registers.
+pub fn base_address() -> PhysAddr {
+    let base = unsafe { Msr::new(IA32_APIC_BASE_MSR).read() };
+    PhysAddr::new(base & APIC_BASE_ADDRESS_MASK)
+}
+
+/// Maps the local APIC registers. Requires the VMA manager. Does nothing if they are mapped already.
+pub fn init() {
+    REGISTERS.init_once(|| {
+        unsafe { mmio::map_mmio(base_address(), REGISTERS_SIZE, CachePolicy::Uncached) }
+            .expect("mapping the local APIC registers failed")
+    });
+}
+
+fn registers() -> &'static MmioRegion {
+    REGISTERS.try_get().expect("local APIC registers not mapped")
+}
+
+/// Reads a local APIC register. Requires `init`.
+pub fn read(register: usize) -> u32 {
+    registers().read(register)
+}
+
+/// Writes a local APIC register.
+///
+/// # Safety
+/// Writing a register can change how interrupts are delivered to this CPU. The caller must make sure the
+/// written value leaves interrupt delivery in a consistent state.
+pub unsafe fn write(register: usize, value: u32) {
+    registers().write(register, value)
+}
+
+/// Software-enables the local APIC, which is required for its LVT entries to deliver interrupts.
+pub fn enable() {
+    let value = read(REG_SPURIOUS_VECTOR);
+    unsafe { write(REG_SPURIOUS_VECTOR, value | SPURIOUS_VECTOR_ENABLE | u32::from(SPURIOUS_VECTOR)) };
+}
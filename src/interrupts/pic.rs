@@ -0,0 +1,99 @@
+// 8259 PIC line masking and spurious interrupt detection
+// A spurious interrupt is raised when an IRQ is deasserted before the CPU acknowledges it. The PIC then reports
+// its lowest priority line (IRQ 7 on the primary, IRQ 15 on the secondary) without setting the corresponding
+// bit in its In-Service Register. Spurious interrupts must not be acknowledged on the PIC that raised them, but
+// a spurious IRQ 15 still needs an EOI on the primary PIC, since the primary did see a real cascade interrupt.
+
+use super::{irq, PICS};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+const PRIMARY_COMMAND_PORT: u16 = 0x20;
+const SECONDARY_COMMAND_PORT: u16 = 0xA0;
+
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+const CMD_READ_ISR: u8 = 0x0B;              // OCW3: next read of the command port returns the ISR
+
+static SPURIOUS_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// Initialize both PICs and mask every line except the cascade. Lines are unmasked as handlers are registered.
+pub fn init() {
+    let mut pics = PICS.lock();
+    unsafe {
+        pics.initialize();                  // Unsafe - undefined behavior if PIC is misconfigured
+        let [primary, secondary] = (!(1u16 << irq::CASCADE)).to_le_bytes();
+        pics.write_masks(primary, secondary);
+    }
+}
+
+/// Masks the given IRQ line so the PIC no longer forwards its interrupts.
+pub fn mask_irq(line: u8) {
+    update_mask(line, true);
+}
+
+/// Unmasks the given IRQ line.
+pub fn unmask_irq(line: u8) {
+    update_mask(line, false);
+}
+
+/// Returns true if the given IRQ line is masked.
+pub fn is_masked(line: u8) -> bool {
+    let masks = unsafe { PICS.lock().read_masks() };
+    u16::from_le_bytes(masks) & (1 << line) != 0
+}
+
+/// Returns the number of spurious interrupts seen on IRQ 7 and IRQ 15.
+pub fn spurious_count() -> u64 {
+    SPURIOUS_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+fn update_mask(line: u8, masked: bool) {
+    assert!(usize::from(line) < irq::IRQ_LINES, "invalid IRQ line {}", line);
+
+    let mut pics = PICS.lock();
+    unsafe {
+        let mask = u16::from_le_bytes(pics.read_masks());
+        let mask = if masked { mask | (1 << line) } else { mask & !(1 << line) };
+        let [primary, secondary] = mask.to_le_bytes();
+        pics.write_masks(primary, secondary);
+    }
+}
+
+/// Reads the combined In-Service Register of both PICs (secondary in the high byte).
+fn read_isr() -> u16 {
+    let mut primary: Port<u8> = Port::new(PRIMARY_COMMAND_PORT);
+    let mut secondary: Port<u8> = Port::new(SECONDARY_COMMAND_PORT);
+    unsafe {
+        primary.write(CMD_READ_ISR);
+        secondary.write(CMD_READ_ISR);
+        u16::from_le_bytes([primary.read(), secondary.read()])
+    }
+}
+
+/// Checks whether an interrupt on the given line is spurious. Spurious interrupts are counted and the EOI the
+/// primary PIC still expects for a spurious IRQ 15 is sent here, so the caller must neither handle nor acknowledge them.
+pub(super) fn is_spurious(line: u8) -> bool {
+    if line != 7 && line != 15 {
+        return false;
+    }
+    if read_isr() & (1 << line) != 0 {
+        return false;
+    }
+
+    SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    if line == 15 {
+        let mut primary: Port<u8> = Port::new(PRIMARY_COMMAND_PORT);
+        unsafe { primary.write(CMD_END_OF_INTERRUPT) };
+    }
+    true
+}
+
+#[test_case]
+fn test_mask_and_unmask() {
+    let line = irq::LPT2;
+    assert!(is_masked(line));
+    unmask_irq(line);
+    assert!(!is_masked(line));
+    mask_irq(line);
+    assert!(is_masked(line));
+}
@@ -0,0 +1,123 @@
+// Per-vector interrupt statistics
+// Every instrumented handler counts its invocations and measures its run time with the TSC, similar to
+// /proc/interrupts on Linux. Counters are plain atomics so they can be updated from any interrupt context
+// without locking. Handlers can also report dropped events (e.g. a full queue), which helps to spot storms.
+
+use crate::{serial_println, time::tsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Invocations running longer than this are counted as slow.
+pub const SLOW_HANDLER_THRESHOLD_NS: u64 = 100_000;
+
+const VECTORS: usize = 256;
+
+/// Raw counters for a single interrupt vector.
+struct VectorStats {
+    count: AtomicU64,
+    total_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+    slow: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl VectorStats {
+    const fn new() -> Self {
+        VectorStats {
+            count: AtomicU64::new(0),
+            total_cycles: AtomicU64::new(0),
+            max_cycles: AtomicU64::new(0),
+            slow: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+}
+
+static STATS: [VectorStats; VECTORS] = [const { VectorStats::new() }; VECTORS];
+
+/// Point-in-time copy of the statistics of a vector, with times converted to nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorSnapshot {
+    pub vector: u8,
+    pub count: u64,
+    pub average_ns: u64,
+    pub max_ns: u64,
+    pub slow: u64,
+    pub dropped: u64,
+}
+
+/// Measures a handler invocation from creation until it is dropped.
+pub struct Measurement {
+    vector: u8,
+    start: u64,
+}
+
+impl Drop for Measurement {
+    fn drop(&mut self) {
+        record(self.vector, tsc::read().wrapping_sub(self.start));
+    }
+}
+
+/// Starts measuring a handler invocation for the given vector. Keep the returned value alive for the
+/// duration of the handler.
+pub fn measure(vector: u8) -> Measurement {
+    Measurement { vector, start: tsc::read() }
+}
+
+/// Counts an invocation without timing it, for handlers that never return.
+pub fn count(vector: u8) {
+    STATS[usize::from(vector)].count.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records an event that a handler for the given vector had to drop.
+pub fn record_dropped(vector: u8) {
+    STATS[usize::from(vector)].dropped.fetch_add(1, Ordering::Relaxed);
+}
+
+fn record(vector: u8, cycles: u64) {
+    let stats = &STATS[usize::from(vector)];
+    stats.count.fetch_add(1, Ordering::Relaxed);
+    stats.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+    stats.max_cycles.fetch_max(cycles, Ordering::Relaxed);
+    if tsc::cycles_to_ns(cycles) > SLOW_HANDLER_THRESHOLD_NS {
+        stats.slow.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the statistics of the given vector.
+pub fn snapshot(vector: u8) -> VectorSnapshot {
+    let stats = &STATS[usize::from(vector)];
+    let count = stats.count.load(Ordering::Relaxed);
+    let total_cycles = stats.total_cycles.load(Ordering::Relaxed);
+
+    VectorSnapshot {
+        vector,
+        count,
+        average_ns: tsc::cycles_to_ns(total_cycles.checked_div(count).unwrap_or(0)),
+        max_ns: tsc::cycles_to_ns(stats.max_cycles.load(Ordering::Relaxed)),
+        slow: stats.slow.load(Ordering::Relaxed),
+        dropped: stats.dropped.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns an iterator over the statistics of all vectors that have seen activity.
+pub fn active_vectors() -> impl Iterator<Item = VectorSnapshot> {
+    (0..=u8::MAX)
+        .map(snapshot)
+        .filter(|s| s.count > 0 || s.dropped > 0)
+}
+
+/// Prints a table of all active vectors to the serial port.
+pub fn print() {
+    serial_println!("{:>6} {:>12} {:>12} {:>12} {:>8} {:>8}", "vector", "count", "avg ns", "max ns", "slow", "dropped");
+    for s in active_vectors() {
+        serial_println!("{:>6} {:>12} {:>12} {:>12} {:>8} {:>8}",
+            s.vector, s.count, s.average_ns, s.max_ns, s.slow, s.dropped);
+    }
+}
+
+#[test_case]
+fn test_breakpoint_is_counted() {
+    let before = snapshot(super::BREAKPOINT_VECTOR).count;
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(snapshot(super::BREAKPOINT_VECTOR).count, before + 1);
+}
@@ -0,0 +1,76 @@
+// Register-saving exception entry
+// The x86-interrupt calling convention only exposes the frame pushed by the CPU. Exceptions that need the full
+// register state of the interrupted code (e.g. for the debugger) enter through a naked stub instead, which pushes
+// all general purpose registers next to the CPU's frame, passes the whole block to the handler and restores it
+// afterwards - so any change the handler makes to the registers or flags takes effect on return.
+
+use core::fmt;
+use x86_64::structures::idt::InterruptStackFrameValue;
+
+/// General purpose registers of the interrupted code, followed by the frame pushed by the CPU.
+/// The field order mirrors the push order of the entry stubs.
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+impl TrapFrame {
+    /// Returns the general purpose registers with their names, in the conventional order.
+    pub fn registers(&self) -> [(&'static str, u64); 16] {
+        [
+            ("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx), ("rdx", self.rdx),
+            ("rsi", self.rsi), ("rdi", self.rdi), ("rbp", self.rbp), ("rsp", self.stack_frame.stack_pointer.as_u64()),
+            ("r8", self.r8), ("r9", self.r9), ("r10", self.r10), ("r11", self.r11),
+            ("r12", self.r12), ("r13", self.r13), ("r14", self.r14), ("r15", self.r15),
+        ]
+    }
+}
+
+impl fmt::Debug for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("TrapFrame");
+        for (name, value) in self.registers() {
+            debug.field(name, &format_args!("{:#x}", value));
+        }
+        debug.field("stack_frame", &self.stack_frame).finish()
+    }
+}
+
+/// Defines a naked exception entry stub that calls `$handler(&mut TrapFrame)` and returns with `iretq`.
+/// Only for exceptions without an error code.
+macro_rules! trap_entry {
+    ($name:ident => $handler:path) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            core::arch::naked_asm!(
+                "push rax", "push rbx", "push rcx", "push rdx", "push rsi", "push rdi", "push rbp",
+                "push r8", "push r9", "push r10", "push r11", "push r12", "push r13", "push r14", "push r15",
+                // The CPU aligned the stack to 16 bytes before pushing its 5 word frame - 20 words keep it aligned
+                "mov rdi, rsp",
+                "cld",
+                "call {handler}",
+                "pop r15", "pop r14", "pop r13", "pop r12", "pop r11", "pop r10", "pop r9", "pop r8",
+                "pop rbp", "pop rdi", "pop rsi", "pop rdx", "pop rcx", "pop rbx", "pop rax",
+                "iretq",
+                handler = sym $handler,
+            );
+        }
+    };
+}
+
+pub(super) use trap_entry;
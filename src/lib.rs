@@ -6,6 +6,7 @@
 #![feature(abi_x86_interrupt)]                  // Enable the x86-interrupt calling convention for interrupt handlers
 
 use core::panic::PanicInfo;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
 
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
@@ -13,12 +14,20 @@ use bootloader::{entry_point, BootInfo};
 extern crate alloc;
 
 pub mod serial;
+pub mod sync;
 pub mod vga_buffer;
 pub mod interrupts;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod backtrace;
+pub mod debugger;
 pub mod task;
+pub mod rtc;
+pub mod syscall;
+pub mod time;
+pub mod user;
+pub mod watchdog;
 
 #[cfg(test)]
 entry_point!(test_kernel_main);
@@ -52,6 +61,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {           // Test runner function
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    backtrace::print();
     exit_qemu(QemuExitCode::Failed);
     
     idle_loop();
@@ -59,8 +69,9 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-pub fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+pub fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    unsafe { memory::init(x86_64::VirtAddr::new(boot_info.physical_memory_offset)) };
     test_main();
 
     idle_loop();
@@ -69,13 +80,26 @@ pub fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
 /// Initialize all components of the OS
 pub fn init() {
     gdt::init();
+    syscall::entry::init();
+    interrupts::pic::init();            // Masks all lines - must come before handlers are registered
     interrupts::idt_init();
-    unsafe {
-        interrupts::PICS.lock().initialize()    // Unsafe - undefined behavior if PIC is misconfigured
-    };
+    time::init();
+    rtc::init();
+    task::keyboard::init();
     x86_64::instructions::interrupts::enable();
 }
 
+/// Replace the boot stacks with guard-paged stacks and start tracking stack usage.
+/// Requires the memory mapping to be initialized.
+pub fn init_stacks(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), memory::stack::StackError> {
+    memory::stack::track_boot_stack();
+    gdt::init_stacks(mapper, frame_allocator)?;
+    syscall::entry::init_stack(mapper, frame_allocator)
+}
+
 /// Idle loop to wait until next interrupt. Causes CPU to enter sleep, consuming less energy.
 pub fn idle_loop() -> ! {
     loop {
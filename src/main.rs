@@ -29,10 +29,21 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe{ memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    unsafe { memory::frame::init(&boot_info.memory_map) };
+    let mut frame_allocator = memory::frame::GlobalFrameAllocator;
 
     allocator::heap_init(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::vma::init();
+    memory::address_space::init(&mut frame_allocator);
+    memory::mmio::init();
+    memory::protection::init();
+    rust_os::init_stacks(&mut mapper, &mut frame_allocator)
+        .expect("stack initialization failed");
+    rust_os::interrupts::deferred::init();
+
+    rust_os::watchdog::init(rust_os::watchdog::DEFAULT_TIMEOUT);
+    rust_os::debugger::init();
 
     // Allocate a number on the heap to test the allocator.
     let heap_value = Box::new(41);
@@ -70,8 +81,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {           // ! is the "never" type, indicating this function will not return
-    // This function is called on panic. Here we simply print the panic info and halt.
+    // This function is called on panic. Here we simply print the panic info and a backtrace (to serial) and halt.
     println!("{}", info);
+    rust_os::backtrace::print();
     rust_os::idle_loop();
 }
 
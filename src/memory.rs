@@ -1,17 +1,118 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::{MapToError, UnmapError}, page::PageRange, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable,
+        Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
     PhysAddr,
     VirtAddr
 };
 
+pub mod address_space;
+pub mod cow;
+pub mod dma;
+pub mod frame;
+pub mod inspect;
+pub mod mmio;
+pub mod protection;
+pub mod stats;
+pub mod stack;
+pub mod vma;
+
+/// Virtual address at which the bootloader mapped the complete physical memory. Set by `init`.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Physical address of the level 4 table the kernel booted with. Set by `init`.
+static KERNEL_P4_FRAME: AtomicU64 = AtomicU64::new(0);
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_P4_FRAME.store(x86_64::registers::control::Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+    // Make kernel writes to read-only pages fault as well, copy-on-write depends on it
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+    // NO_EXECUTE is a reserved bit without NXE. The bootloader already sets it, but don't depend on that
+    let nx_supported = core::arch::x86_64::__cpuid(0x8000_0001).edx & (1 << 20) != 0;
+    assert!(nx_supported, "CPU doesn't support no-execute pages");
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
     }
 }
 
+/// Returns a mapper for the kernel's page table, for code that isn't handed the one returned by `init`.
+///
+/// # Safety
+/// The mapper aliases the one returned by `init`. The caller must make sure the two don't modify the same page
+/// table entries at the same time - the VMA manager does so for the areas it hands out.
+pub(crate) unsafe fn kernel_page_table() -> OffsetPageTable<'static> {
+    let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    let p4_frame = PhysAddr::new(KERNEL_P4_FRAME.load(Ordering::Relaxed));
+    assert!(!offset.is_null() && !p4_frame.is_null(), "memory not initialized");
+    unsafe { OffsetPageTable::new(&mut *(offset + p4_frame.as_u64()).as_mut_ptr(), offset) }
+}
+
+/// Returns the virtual address through which the given physical address can be accessed.
+/// Panics if `init` has not been called yet.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    assert!(offset != 0, "physical memory offset not initialized");
+    VirtAddr::new(offset + addr.as_u64())
+}
+
+/// An entry visited by `walk_page_tables`.
+#[derive(Debug, Clone, Copy)]
+pub struct WalkEntry {
+    pub level: u8,
+    pub index: u16,
+    pub addr: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+/// Walks the active page tables for the given virtual address, calling `f` for each entry from the level 4 table
+/// down. Stops after the first entry that is not present or maps a huge page.
+/// Returns false without calling `f` if the page tables can't be inspected yet because `init` wasn't called.
+pub fn walk_page_tables(addr: VirtAddr, mut f: impl FnMut(WalkEntry)) -> bool {
+    use x86_64::registers::control::Cr3;
+
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return false;
+    }
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut table_addr = level_4_table_frame.start_address();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, index) in (1..=4).rev().zip(indexes) {
+        let table_ptr: *const PageTable = VirtAddr::new(offset + table_addr.as_u64()).as_ptr();
+        let table = unsafe { &*table_ptr };
+        let entry = &table[index];
+        f(WalkEntry { level, index: u16::from(index), addr: entry.addr(), flags: entry.flags() });
+
+        // A huge page has no lower levels
+        if !entry.flags().contains(PageTableFlags::PRESENT) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        table_addr = entry.addr();
+    }
+    true
+}
+
+/// Returns true if the given virtual address is mapped in the active page table.
+/// Before `init` the page tables can't be inspected, so no address is known to be mapped.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    let mut mapped = false;
+    walk_page_tables(addr, |entry| {
+        mapped = entry.flags.contains(PageTableFlags::PRESENT)
+            && (entry.level == 1 || entry.flags.contains(PageTableFlags::HUGE_PAGE));
+    });
+    mapped
+}
+
 /// Returns a mutable reference to the active level 4 page table. Called only from init.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -60,7 +161,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     let mut frame = level_4_table_frame;
 
     // traverse the multi-level page table
-    for &index in &table_indexes {
+    for (level, &index) in (1..=4u32).rev().zip(&table_indexes) {
         // convert the frame into a page table reference
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -71,7 +172,11 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            Err(FrameError::HugeFrame) => {
+                // A huge page in the level 3 table maps 1 GiB, in the level 2 table 2 MiB
+                let page_size = Size4KiB::SIZE << (9 * (level - 1));
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
         };
     }
 
@@ -91,46 +196,114 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-/// A FrameAllocator that uses BootInfo to determine usable frames.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+/// Returns true if the CPU can map 1 GiB pages.
+pub fn supports_1gib_pages() -> bool {
+    let extended = core::arch::x86_64::__cpuid(0x8000_0000).eax;
+    extended >= 0x8000_0001 && core::arch::x86_64::__cpuid(0x8000_0001).edx & (1 << 26) != 0
 }
 
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the
-    /// passed memory map is valid. The main requirement is that all frames
-    /// that are marked as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
+/// Maps `count` consecutive pages starting at `page` to consecutive frames starting at `frame`. Works for all page
+/// sizes; 1 GiB pages need CPU support (see `supports_1gib_pages`).
+///
+/// # Safety
+/// The frames must not be in use by anything else, see `Mapper::map_to`.
+pub unsafe fn map_contiguous<S: PageSize + core::fmt::Debug>(
+    mapper: &mut impl Mapper<S>,
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    count: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<S>> {
+    for i in 0..count {
+        unsafe { mapper.map_to(page + i, frame + i, flags, frame_allocator)?.flush() };
     }
+    Ok(())
+}
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+/// Maps the given pages to newly allocated frames of the same size, e.g. to back a large buffer with 2 MiB pages.
+pub fn map_new<S: PageSize + core::fmt::Debug>(
+    mapper: &mut impl Mapper<S>,
+    pages: PageRange<S>,
+    flags: PageTableFlags,
+    frame_allocator: &mut (impl FrameAllocator<S> + FrameAllocator<Size4KiB>),
+) -> Result<(), MapToError<S>> {
+    for page in pages {
+        let frame = FrameAllocator::<S>::allocate_frame(frame_allocator).ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
+    Ok(())
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator { 
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+/// Maps `size` bytes of physical memory starting at `phys` to virtual memory starting at `virt`, using the largest
+/// pages that the alignment of both addresses allows. Both addresses must be 4 KiB aligned and have the same offset
+/// within a 2 MiB page for huge pages to be used.
+///
+/// # Safety
+/// See `Mapper::map_to`.
+pub unsafe fn map_physical_range<M>(
+    mapper: &mut M,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB>,
+{
+    assert!(virt.is_aligned(Size4KiB::SIZE) && phys.is_aligned(Size4KiB::SIZE), "addresses must be page aligned");
+
+    let allow_1gib = supports_1gib_pages();
+    let mut offset = 0;
+    while offset < size {
+        let (virt, phys, remaining) = (virt + offset, phys + offset, size - offset);
+        let fits = |page_size: u64| {
+            virt.is_aligned(page_size) && phys.is_aligned(page_size) && remaining >= page_size
+        };
+        // Huge page mapping errors are reported as their 4 KiB equivalents
+        offset += if allow_1gib && fits(Size1GiB::SIZE) {
+            let (page, frame) = (Page::<Size1GiB>::containing_address(virt), PhysFrame::containing_address(phys));
+            unsafe { map_contiguous(mapper, page, frame, 1, flags, frame_allocator) }.map_err(huge_map_error)?;
+            Size1GiB::SIZE
+        } else if fits(Size2MiB::SIZE) {
+            let (page, frame) = (Page::<Size2MiB>::containing_address(virt), PhysFrame::containing_address(phys));
+            unsafe { map_contiguous(mapper, page, frame, 1, flags, frame_allocator) }.map_err(huge_map_error)?;
+            Size2MiB::SIZE
+        } else {
+            let (page, frame) = (Page::<Size4KiB>::containing_address(virt), PhysFrame::containing_address(phys));
+            unsafe { map_contiguous(mapper, page, frame, 1, flags, frame_allocator) }?;
+            Size4KiB::SIZE
+        };
+    }
+    Ok(())
+}
+
+fn huge_map_error<S: PageSize>(error: MapToError<S>) -> MapToError<Size4KiB> {
+    match error {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Unmaps the given pages and returns their frames to `frame_deallocator`. Stops at the first page that can't be
+/// unmapped, leaving the pages after it mapped.
+///
+/// # Safety
+/// Nothing may access the pages or their frames anymore, and the frames must not be mapped anywhere else.
+pub unsafe fn unmap_pages<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    pages: PageRange<S>,
+    frame_deallocator: &mut impl FrameDeallocator<S>,
+) -> Result<(), UnmapError> {
+    for page in pages {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        unsafe { frame_deallocator.deallocate_frame(frame) };
+    }
+    address_space::kernel_mappings_changed();
+    Ok(())
+}
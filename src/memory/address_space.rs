@@ -0,0 +1,306 @@
+// Address spaces
+// Each address space has its own level 4 page table. The user half - the P4 entries in `USER_P4_ENTRIES` - is
+// private to it, while every other entry is copied from the kernel's table when the address space is created, so
+// the kernel's code, heap, stacks and physical memory window stay mapped after switching. Mappings added below an
+// existing kernel P4 entry show up in all address spaces because the lower level tables are shared; `init`
+// pre-allocates the entries of the dynamic VMA region so this holds for the areas created later as well.
+// If the CPU supports process-context identifiers, every address space gets its own PCID and its TLB entries
+// survive switching to another one. Switching only keeps them if they can't be stale: not if the address space's
+// tables were touched through `mapper` since it was last loaded, and not if a kernel mapping was removed or
+// downgraded since, which `unmap_pages` and the VMA manager report through `kernel_mappings_changed`. Otherwise
+// the entries tagged with its PCID are flushed on the switch - which is also what makes reusing a PCID safe.
+
+use super::{cow, phys_to_virt, vma, KERNEL_P4_FRAME, PHYSICAL_MEMORY_OFFSET};
+use super::frame::GlobalFrameAllocator;
+use crate::sync::IrqMutex;
+use core::arch::asm;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::{
+    instructions::tlb::{self, Pcid},
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
+    structures::paging::{
+        page_table::PageTableEntry, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PageTableIndex, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Level 4 entries that make up the user half of an address space: 0x0800_0000_0000 to 0x4000_0000_0000.
+/// The entries below it are left to the kernel image and the bootloader's mappings.
+pub const USER_P4_ENTRIES: Range<usize> = 16..128;
+
+/// Number of PCIDs, the kernel's address space uses PCID 0.
+const PCID_COUNT: usize = 4096;
+
+/// CR3 bit that keeps the TLB entries tagged with the loaded PCID.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// Generation value that forces a flush on the next switch.
+const NEEDS_FLUSH: u64 = u64::MAX;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Incremented whenever a mapping that other address spaces may have cached is removed or downgraded.
+static MAPPINGS_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Allocated PCIDs, one bit each.
+static PCIDS: IrqMutex<[u64; PCID_COUNT / 64]> = IrqMutex::new([0; PCID_COUNT / 64]);
+
+/// A level 4 page table with the kernel half shared with the kernel's table.
+#[derive(Debug)]
+pub struct AddressSpace {
+    p4_frame: PhysFrame,
+    pcid: Option<Pcid>,
+    /// Value of `MAPPINGS_GENERATION` when the TLB entries of the PCID were last flushed
+    flushed: AtomicU64,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user half. Returns `None` if no frame is left for its page table.
+    /// Its page tables come from the global frame allocator, which `Drop` returns them to.
+    pub fn new() -> Option<Self> {
+        let p4_frame = GlobalFrameAllocator.allocate_frame()?;
+        let table = unsafe { &mut *table_ptr(p4_frame) };
+        let kernel_table = unsafe { &*table_ptr(kernel_p4_frame()) };
+        for (index, entry) in table.iter_mut().enumerate() {
+            if USER_P4_ENTRIES.contains(&index) {
+                entry.set_unused();
+            } else {
+                entry.clone_from(&kernel_table[index]);
+            }
+        }
+        Some(AddressSpace { p4_frame, pcid: allocate_pcid(), flushed: AtomicU64::new(NEEDS_FLUSH) })
+    }
+
+    /// Physical frame of the level 4 table.
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4_frame
+    }
+
+    pub fn pcid(&self) -> Option<Pcid> {
+        self.pcid
+    }
+
+    /// Returns a mapper for the address space. It works whether or not the address space is active.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        self.flushed.store(NEEDS_FLUSH, Ordering::Relaxed);
+        let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+        unsafe { OffsetPageTable::new(&mut *table_ptr(self.p4_frame), offset) }
+    }
+
+    /// Returns true if the CPU is using this address space.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4_frame
+    }
+
+    /// Creates a copy of the address space whose user half shares this one's frames. Writable pages become
+    /// read-only copy-on-write pages in both, so the first write to one of them makes a private copy.
+    /// Returns `None` if no frames are left for the page tables.
+    pub fn fork(&mut self) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        let mut child_mapper = child.mapper();
+        let mapped = for_each_user_page(self.p4_frame, |page, entry| {
+            let flags = cow::shared_flags(entry.flags());
+            let frame = entry.frame().unwrap();
+            let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+                | (flags & PageTableFlags::USER_ACCESSIBLE);
+            unsafe { child_mapper.map_to_with_table_flags(page, frame, flags, table_flags, &mut GlobalFrameAllocator) }
+                .map_err(|_| ())?
+                .ignore();
+            // Only a page the child maps takes a reference, so a failed fork leaves the counts balanced
+            cow::share(entry);
+            Ok::<(), ()>(())
+        });
+
+        // Pages that were writable are read-only now
+        self.flushed.store(NEEDS_FLUSH, Ordering::Relaxed);
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        // Dropping the child releases the pages it got before the failure
+        mapped.ok().map(|()| child)
+    }
+
+    /// Unmaps all pages of the user half and drops the references to their frames. The page tables stay allocated
+    /// until the address space is dropped, which releases the pages as well.
+    pub fn release_user_pages(&mut self) {
+        let _ = for_each_user_page(self.p4_frame, |_, entry| {
+            let frame = entry.frame().unwrap();
+            entry.set_unused();
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            Ok::<(), ()>(())
+        });
+        self.flushed.store(NEEDS_FLUSH, Ordering::Relaxed);
+        if self.is_active() {
+            tlb::flush_all();
+        }
+    }
+
+    /// Makes this the active address space.
+    ///
+    /// # Safety
+    /// The code, data and stack in use must be mapped in the address space, which holds for everything in the
+    /// kernel half.
+    pub unsafe fn switch_to(&self) {
+        unsafe { load_cr3(self.p4_frame, self.pcid, Some(&self.flushed)) };
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Releases the pages of the user half and frees its page tables and the level 4 table. The address space owns
+    /// the frames its user half maps: each is freed once no other address space shares it.
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        self.release_user_pages();
+        let table = unsafe { &mut *table_ptr(self.p4_frame) };
+        for index in USER_P4_ENTRIES {
+            free_table(&mut table[index], 3, &mut GlobalFrameAllocator);
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.p4_frame) };
+        if let Some(pcid) = self.pcid {
+            let value = usize::from(pcid.value());
+            PCIDS.lock()[value / 64] &= !(1 << (value % 64));
+        }
+    }
+}
+
+/// Calls `f` with every present 4 KiB page in the user half of the page table rooted at `p4_frame` and its level 1
+/// entry. Stops at the first error. Huge pages in the user half are not supported.
+fn for_each_user_page<E>(
+    p4_frame: PhysFrame,
+    mut f: impl FnMut(Page, &mut PageTableEntry) -> Result<(), E>,
+) -> Result<(), E> {
+    let p4 = unsafe { &mut *table_ptr(p4_frame) };
+    for p4_index in USER_P4_ENTRIES {
+        let Some(p3) = lower_table(&p4[p4_index]) else { continue };
+        for p3_index in 0..512 {
+            let Some(p2) = lower_table(&p3[p3_index]) else { continue };
+            for p2_index in 0..512 {
+                let Some(p1) = lower_table(&p2[p2_index]) else { continue };
+                for p1_index in 0..512 {
+                    let entry = &mut p1[p1_index];
+                    if !entry.flags().contains(PageTableFlags::PRESENT) {
+                        continue;
+                    }
+                    let page = Page::from_page_table_indices(
+                        PageTableIndex::new(p4_index as u16),
+                        PageTableIndex::new(p3_index as u16),
+                        PageTableIndex::new(p2_index as u16),
+                        PageTableIndex::new(p1_index as u16),
+                    );
+                    f(page, entry)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the table a present entry points to. Panics if the entry maps a huge page.
+fn lower_table(entry: &PageTableEntry) -> Option<&'static mut PageTable> {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+    assert!(!flags.contains(PageTableFlags::HUGE_PAGE), "huge pages in the user half are not supported");
+    Some(unsafe { &mut *table_ptr(PhysFrame::containing_address(entry.addr())) })
+}
+
+/// Frees the level `level` table an entry points to, including the tables below it, and clears the entry.
+/// Leaves entries that map memory alone: huge pages and the entries of level 1 tables (`level` 0).
+fn free_table(entry: &mut PageTableEntry, level: u8, frame_deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+    let flags = entry.flags();
+    if level == 0 || !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+        return;
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    let table = unsafe { &mut *table_ptr(frame) };
+    for lower in table.iter_mut() {
+        free_table(lower, level - 1, frame_deallocator);
+    }
+    unsafe { frame_deallocator.deallocate_frame(frame) };
+    entry.set_unused();
+}
+
+/// Enables PCIDs if the CPU supports them and allocates the kernel P4 entries that address spaces can't pick up
+/// later. Requires `memory::init` and the frame allocator.
+pub fn init(frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
+    let kernel_table = unsafe { &mut *table_ptr(kernel_p4_frame()) };
+    // Address spaces don't copy these entries, so anything the kernel mapped there would vanish on a switch
+    for index in USER_P4_ENTRIES {
+        assert!(kernel_table[index].is_unused(), "kernel mapping in the user half at P4 entry {}", index);
+    }
+    let start = VirtAddr::new(vma::DYNAMIC_REGION_START);
+    let end = start + (vma::DYNAMIC_REGION_SIZE - 1);
+    for index in usize::from(start.p4_index())..=usize::from(end.p4_index()) {
+        let entry = &mut kernel_table[PageTableIndex::new(index as u16)];
+        if entry.is_unused() {
+            let frame = frame_allocator.allocate_frame().expect("out of frames for kernel page tables");
+            unsafe { (*table_ptr(frame)).zero() };
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+
+    let pcid_supported = core::arch::x86_64::__cpuid(1).ecx & (1 << 17) != 0;
+    if pcid_supported && Cr3::read().1.is_empty() {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+        PCID_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Returns true if address spaces are tagged with PCIDs.
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records that a mapping was removed or its permissions reduced, so no address space may keep TLB entries from
+/// before. Not needed for mappings that only the active address space can have cached.
+pub fn kernel_mappings_changed() {
+    MAPPINGS_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Switches back to the kernel's own address space.
+pub fn switch_to_kernel() {
+    unsafe { load_cr3(kernel_p4_frame(), None, None) };
+}
+
+/// Loads CR3, keeping the TLB entries of the PCID unless they may be stale. `flushed` tracks the generation at
+/// which they were last flushed; without it they are always flushed.
+unsafe fn load_cr3(p4_frame: PhysFrame, pcid: Option<Pcid>, flushed: Option<&AtomicU64>) {
+    if !pcid_enabled() {
+        unsafe { Cr3::write(p4_frame, Cr3Flags::empty()) };
+        return;
+    }
+    // The kernel and address spaces that ran out of PCIDs share PCID 0, so its entries are never kept
+    let generation = MAPPINGS_GENERATION.load(Ordering::Relaxed);
+    let mut value = p4_frame.start_address().as_u64();
+    if let (Some(pcid), Some(flushed)) = (pcid, flushed) {
+        value |= u64::from(pcid.value());
+        if flushed.swap(generation, Ordering::Relaxed) == generation {
+            value |= CR3_NO_FLUSH;
+        }
+    }
+    unsafe { asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags)) };
+}
+
+fn allocate_pcid() -> Option<Pcid> {
+    if !pcid_enabled() {
+        return None;
+    }
+    let mut pcids = PCIDS.lock();
+    // PCID 0 belongs to the kernel
+    let value = (1..PCID_COUNT).find(|value| pcids[value / 64] & (1 << (value % 64)) == 0)?;
+    pcids[value / 64] |= 1 << (value % 64);
+    Pcid::new(value as u16).ok()
+}
+
+fn kernel_p4_frame() -> PhysFrame {
+    let addr = KERNEL_P4_FRAME.load(Ordering::Relaxed);
+    assert!(addr != 0, "kernel page table not initialized");
+    PhysFrame::containing_address(PhysAddr::new(addr))
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
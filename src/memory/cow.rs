@@ -0,0 +1,99 @@
+// Copy-on-write
+// `AddressSpace::fork` shares the frames of the user half instead of copying them. Writable pages are mapped
+// read-only in both address spaces and marked with the `COW` bit, one of the page table entry bits left to the
+// operating system, and the frame allocator counts the references to each shared frame.
+// The first write to such a page raises a protection violation page fault, which `handle_page_fault` resolves: it
+// copies the frame if it is still shared, or takes it over if the other references are gone, and maps the page
+// writable again. CR0.WP is set by `memory::init`, so writes from the kernel fault as well.
+
+use super::frame::{try_with_frame_allocator, with_frame_allocator};
+use super::phys_to_virt;
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        page_table::PageTableEntry, FrameAllocator, FrameDeallocator, PageSize, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// Marks a read-only page whose frame is shared copy-on-write.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Returns the flags of a shared mapping of a page mapped with `flags`: writable pages become read-only
+/// copy-on-write pages.
+pub fn shared_flags(mut flags: PageTableFlags) -> PageTableFlags {
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags.remove(PageTableFlags::WRITABLE);
+        flags.insert(COW);
+    }
+    flags
+}
+
+/// Turns the mapping in `entry` into a shared one and adds a reference to its frame. Returns the flags the other
+/// mapping of the frame should use, which `shared_flags` computes up front.
+pub fn share(entry: &mut PageTableEntry) -> PageTableFlags {
+    let flags = shared_flags(entry.flags());
+    entry.set_flags(flags);
+    let frame = entry.frame().expect("sharing an entry that doesn't map a 4 KiB frame");
+    with_frame_allocator(|allocator| allocator.share(frame));
+    flags
+}
+
+/// Resolves a write to a copy-on-write page in the active address space. Returns false if the fault has another
+/// cause, or if no frame is left for the copy.
+/// The fault may interrupt code that holds the frame allocator, so the lock is only tried: if it is busy the fault
+/// is reported as a real one rather than deadlocking. Code holding the frame allocator must not write to
+/// copy-on-write pages.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let cow_fault = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(cow_fault) {
+        return false;
+    }
+    let Some(entry) = leaf_entry(Cr3::read().0, addr) else {
+        return false;
+    };
+    let flags = entry.flags();
+    if !flags.contains(COW) {
+        return false;
+    }
+
+    let shared: PhysFrame = entry.frame().expect("copy-on-write entry without a frame");
+    let frame = try_with_frame_allocator(|allocator| {
+        // The last reference can simply be made writable
+        if allocator.ref_count(shared) == 1 {
+            return Some(shared);
+        }
+        let copy: PhysFrame = allocator.allocate_frame()?;
+        unsafe {
+            let source = phys_to_virt(shared.start_address()).as_ptr::<u8>();
+            let destination = phys_to_virt(copy.start_address()).as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(source, destination, Size4KiB::SIZE as usize);
+            allocator.deallocate_frame(shared);
+        }
+        Some(copy)
+    });
+    let Some(frame) = frame.flatten() else {
+        return false;
+    };
+
+    entry.set_addr(frame.start_address(), (flags - COW) | PageTableFlags::WRITABLE);
+    tlb::flush(addr);
+    true
+}
+
+/// Returns the level 1 entry mapping `addr` in the page table rooted at `p4_frame`, or `None` if one of the higher
+/// level entries is not present or maps a huge page.
+fn leaf_entry(p4_frame: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let mut table = unsafe { &mut *phys_to_virt(p4_frame.start_address()).as_mut_ptr::<PageTable>() };
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() };
+    }
+    Some(&mut table[addr.p1_index()])
+}
@@ -0,0 +1,72 @@
+// DMA buffers
+// A device doing DMA accesses physical memory directly, so its buffers must be physically contiguous, and a device
+// that can't address all of physical memory needs them in a low zone: legacy ISA DMA below 16 MiB, 32-bit PCI
+// devices below 4 GiB. `DmaBuffer` allocates such a buffer from the frame allocator, hands the kernel its virtual
+// address in the physical memory window and the device its physical address, and frees the frames when dropped.
+// x86 keeps DMA coherent with the caches, so the window's write-back mapping can be used as it is.
+
+use super::frame::{with_frame_allocator, Zone};
+use super::phys_to_virt;
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// A physically contiguous buffer for DMA. Freed when dropped.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    start: PhysFrame,
+    frames: usize,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates a zeroed buffer of `len` bytes that lies below the limit of `zone` and starts at a multiple of
+    /// `align` bytes. Alignments below 4 KiB are rounded up. Returns `None` if no run of frames that large is free.
+    /// ISA DMA transfers can't cross a 64 KiB boundary, which an alignment to the buffer size rules out.
+    pub fn new(len: usize, zone: Zone, align: usize) -> Option<Self> {
+        assert!(len > 0, "allocating an empty DMA buffer");
+        assert!(align.is_power_of_two(), "DMA buffer alignment must be a power of two");
+        let frame_size = Size4KiB::SIZE as usize;
+        let frames = len.div_ceil(frame_size);
+        let start = with_frame_allocator(|allocator| {
+            allocator.allocate_in_zone(zone, frames, align.max(frame_size) / frame_size)
+        })?;
+
+        let buffer = DmaBuffer { start, frames, len };
+        unsafe { buffer.virt_addr().as_mut_ptr::<u8>().write_bytes(0, frames * frame_size) };
+        Some(buffer)
+    }
+
+    /// Address of the buffer for the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    /// Address of the buffer for the kernel.
+    pub fn virt_addr(&self) -> VirtAddr {
+        phys_to_virt(self.phys_addr())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt_addr().as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        with_frame_allocator(|allocator| unsafe { allocator.deallocate_contiguous(self.start, self.frames) });
+    }
+}
@@ -0,0 +1,352 @@
+// Physical frame allocator
+// Free physical memory is tracked in a bitmap with one bit per 4 KiB frame, built once from the bootloader's
+// memory map. The bitmap itself lives in the first usable region large enough to hold it and is accessed through
+// the physical memory mapping. Single frames are found by scanning the bitmap a word at a time, starting after
+// the last allocation; contiguous runs of frames, including the backing memory of 2 MiB and 1 GiB pages, are found
+// by a linear scan.
+// Frames can be shared, e.g. between address spaces for copy-on-write. Every frame has a count of the extra
+// references to it next to the bitmap, and deallocating a shared frame only drops one reference.
+// Physical memory is split into zones for devices that can't address all of it: DMA16 below 16 MiB for legacy ISA
+// DMA, DMA32 below 4 GiB for 32-bit PCI devices, and normal memory above. Allocations that don't ask for a zone
+// take frames from the highest zone that has them and only fall back to the lower ones when it runs out, which
+// keeps the scarce low memory for the devices that need it.
+// The allocator counts the frames it has handed out and freed since boot, for `memory::stats` to report.
+// The global allocator is reached through `GlobalFrameAllocator`, a handle that can be passed wherever the paging
+// code expects a `FrameAllocator` or `FrameDeallocator`.
+
+use super::phys_to_virt;
+use crate::sync::IrqMutex;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame},
+    PhysAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// First frames above the DMA16 and DMA32 zones.
+const DMA16_END: usize = (16 << 20) / FRAME_SIZE as usize;
+const DMA32_END: usize = (4 << 30) / FRAME_SIZE as usize;
+
+/// The global frame allocator, set up by `init`.
+static FRAME_ALLOCATOR: IrqMutex<Option<BitmapFrameAllocator>> = IrqMutex::new(None);
+
+/// A zone of physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16 MiB, for legacy ISA DMA
+    Dma16,
+    /// Below 4 GiB, for devices with 32-bit DMA addresses
+    Dma32,
+    /// Anywhere in physical memory
+    Normal,
+}
+
+impl Zone {
+    /// Physical address just above the zone, `u64::MAX` for normal memory.
+    pub fn limit(self) -> u64 {
+        match self {
+            Zone::Dma16 => DMA16_END as u64 * FRAME_SIZE,
+            Zone::Dma32 => DMA32_END as u64 * FRAME_SIZE,
+            Zone::Normal => u64::MAX,
+        }
+    }
+
+    /// Frame numbers in the zone that aren't part of a lower zone.
+    fn frames(self) -> Range<usize> {
+        match self {
+            Zone::Dma16 => 0..DMA16_END,
+            Zone::Dma32 => DMA16_END..DMA32_END,
+            Zone::Normal => DMA32_END..usize::MAX,
+        }
+    }
+
+    /// The zones an allocation from this zone may use, in the order they are tried.
+    fn fallbacks(self) -> &'static [Zone] {
+        match self {
+            Zone::Dma16 => &[Zone::Dma16],
+            Zone::Dma32 => &[Zone::Dma32, Zone::Dma16],
+            Zone::Normal => &[Zone::Normal, Zone::Dma32, Zone::Dma16],
+        }
+    }
+}
+
+/// Frame allocator backed by a bitmap of all physical frames. A set bit marks a frame that is in use.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// References to each frame beyond the first, added by `share`
+    shares: &'static mut [u16],
+    /// Number of frames covered by the bitmap
+    frames: usize,
+    /// Number of frames the memory map reports as usable, including the ones holding the bitmap
+    usable: usize,
+    free: usize,
+    /// Frames handed out since boot
+    allocated_total: u64,
+    /// Frames freed since boot
+    freed_total: u64,
+    memory_map: &'static MemoryMap,
+    /// Word index at which the next single frame search starts
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the bitmap from the passed memory map.
+    ///
+    /// # Safety
+    /// All frames marked as `Usable` in the memory map must really be unused, and the physical memory mapping must
+    /// be set up (see `memory::init`).
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let usable_regions = || memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let frames = usable_regions().map(|r| r.range.end_frame_number).max().unwrap_or(0) as usize;
+        let words = frames.div_ceil(BITS_PER_WORD);
+        let bitmap_frames = (words * 8 + frames * 2).div_ceil(FRAME_SIZE as usize) as u64;
+
+        // Place the bitmap and the share counts at the start of the first usable region that can hold them
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .expect("no usable region can hold the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_frame_number;
+
+        let bitmap_addr = phys_to_virt(PhysAddr::new(bitmap_start * FRAME_SIZE));
+        let bitmap = unsafe { core::slice::from_raw_parts_mut(bitmap_addr.as_mut_ptr::<u64>(), words) };
+        bitmap.fill(u64::MAX);
+        let shares_addr = bitmap_addr + words as u64 * 8;
+        let shares = unsafe { core::slice::from_raw_parts_mut(shares_addr.as_mut_ptr::<u16>(), frames) };
+        shares.fill(0);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            shares,
+            frames,
+            usable: 0,
+            free: 0,
+            allocated_total: 0,
+            freed_total: 0,
+            memory_map,
+            next_word: 0,
+        };
+        for region in usable_regions() {
+            let range = region.range.start_frame_number as usize..region.range.end_frame_number as usize;
+            allocator.usable += range.len();
+            range.for_each(|frame| allocator.set_free(frame));
+        }
+        for frame in bitmap_start..bitmap_start + bitmap_frames {
+            allocator.set_used(frame as usize);
+        }
+        allocator
+    }
+
+    /// Number of frames covered by the bitmap, up to the end of the highest usable region.
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    /// Number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Number of frames the memory map reports as usable.
+    pub fn usable_frames(&self) -> usize {
+        self.usable
+    }
+
+    /// Number of frames handed out since boot. Contiguous runs count every frame.
+    pub fn allocated_total(&self) -> u64 {
+        self.allocated_total
+    }
+
+    /// Number of frames freed since boot. Dropping a reference to a shared frame doesn't free it.
+    pub fn freed_total(&self) -> u64 {
+        self.freed_total
+    }
+
+    /// The memory map the allocator was built from.
+    pub fn memory_map(&self) -> &'static MemoryMap {
+        self.memory_map
+    }
+
+    /// Number of free frames in the zone, not counting the lower zones.
+    pub fn free_frames_in(&self, zone: Zone) -> usize {
+        let frames = self.zone_frames(zone);
+        frames.filter(|&frame| !self.is_used(frame)).count()
+    }
+
+    /// Allocates `count` physically contiguous frames whose first frame number is a multiple of `align`.
+    /// Returns the first frame.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        self.allocate_in_zone(Zone::Normal, count, align)
+    }
+
+    /// Like `allocate_contiguous`, but all frames lie below the limit of `zone`.
+    pub fn allocate_in_zone(&mut self, zone: Zone, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(count > 0, "allocating zero frames");
+        assert!(align.is_power_of_two(), "frame alignment must be a power of two");
+        zone.fallbacks().iter().find_map(|&zone| self.allocate_contiguous_in(self.zone_frames(zone), count, align))
+    }
+
+    /// Frees `count` contiguous frames starting at `start`. Frames that are shared lose one reference instead.
+    ///
+    /// # Safety
+    /// The frames must have been allocated from this allocator and the reference being dropped must be unused.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = frame_number(start);
+        for frame in first..first + count {
+            assert!(frame < self.frames && self.is_used(frame), "freeing frame {:#x} that isn't allocated", frame);
+            if self.shares[frame] > 0 {
+                self.shares[frame] -= 1;
+            } else {
+                self.set_free(frame);
+                self.freed_total += 1;
+            }
+        }
+    }
+
+    /// Adds a reference to an allocated frame. It is freed once every reference has been deallocated.
+    pub fn share(&mut self, frame: PhysFrame) {
+        let frame = frame_number(frame);
+        assert!(frame < self.frames && self.is_used(frame), "sharing frame {:#x} that isn't allocated", frame);
+        self.shares[frame] = self.shares[frame].checked_add(1).expect("too many references to a frame");
+    }
+
+    /// Number of references to a frame, 0 if it is free.
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        let frame = frame_number(frame);
+        if frame < self.frames && self.is_used(frame) {
+            1 + usize::from(self.shares[frame])
+        } else {
+            0
+        }
+    }
+
+    /// Allocates a single frame from the highest zone that has one.
+    fn allocate_single(&mut self) -> Option<PhysFrame> {
+        Zone::Normal.fallbacks().iter().find_map(|&zone| self.allocate_single_in(self.zone_frames(zone)))
+    }
+
+    /// Allocates a single frame in `frames`, whose ends must be multiples of the word size. The search starts from
+    /// the word of the last allocation if it lies in the range.
+    fn allocate_single_in(&mut self, frames: Range<usize>) -> Option<PhysFrame> {
+        let words = frames.start / BITS_PER_WORD..frames.end.div_ceil(BITS_PER_WORD);
+        let start = if words.contains(&self.next_word) { self.next_word } else { words.start };
+        let word = (start..words.end)
+            .chain(words.start..start)
+            .find(|&word| self.bitmap[word] != u64::MAX)?;
+
+        let frame = word * BITS_PER_WORD + self.bitmap[word].trailing_ones() as usize;
+        if frame >= self.frames {
+            return None;            // Only the padding bits at the end of the last word are clear
+        }
+        self.set_used(frame);
+        self.allocated_total += 1;
+        self.next_word = word;
+        Some(frame_from_number(frame))
+    }
+
+    /// Allocates `count` contiguous frames in `frames`, starting at a multiple of `align`.
+    fn allocate_contiguous_in(&mut self, frames: Range<usize>, count: usize, align: usize) -> Option<PhysFrame> {
+        let mut start = frames.start.next_multiple_of(align);
+        while start + count <= frames.end {
+            match (start..start + count).rev().find(|&frame| self.is_used(frame)) {
+                // Continue after the used frame, at the next aligned position
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    (start..start + count).for_each(|frame| self.set_used(frame));
+                    self.allocated_total += count as u64;
+                    return Some(frame_from_number(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Frame numbers of the zone that the bitmap covers.
+    fn zone_frames(&self, zone: Zone) -> Range<usize> {
+        let frames = zone.frames();
+        frames.start.min(self.frames)..frames.end.min(self.frames)
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        if !self.is_used(frame) {
+            self.bitmap[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+            self.free -= 1;
+        }
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        if self.is_used(frame) {
+            self.bitmap[frame / BITS_PER_WORD] &= !(1 << (frame % BITS_PER_WORD));
+            self.free += 1;
+        }
+    }
+}
+
+/// Frames larger than 4 KiB are allocated as naturally aligned runs of 4 KiB frames.
+unsafe impl<S: PageSize> FrameAllocator<S> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let count = (S::SIZE / FRAME_SIZE) as usize;
+        let start = if count == 1 { self.allocate_single()? } else { self.allocate_contiguous(count, count)? };
+        Some(PhysFrame::containing_address(start.start_address()))
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        unsafe { self.deallocate_contiguous(start, (S::SIZE / FRAME_SIZE) as usize) };
+    }
+}
+
+fn frame_number(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+fn frame_from_number(number: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(number as u64 * FRAME_SIZE))
+}
+
+/// Set up the global frame allocator from the bootloader's memory map.
+///
+/// # Safety
+/// See `BitmapFrameAllocator::init`. Must only be called once.
+pub unsafe fn init(memory_map: &'static MemoryMap) {
+    let allocator = unsafe { BitmapFrameAllocator::init(memory_map) };
+    let previous = FRAME_ALLOCATOR.lock().replace(allocator);
+    assert!(previous.is_none(), "frame allocator initialized twice");
+}
+
+/// Runs `f` with the global frame allocator. Panics if `init` has not been called.
+#[track_caller]
+pub fn with_frame_allocator<T>(f: impl FnOnce(&mut BitmapFrameAllocator) -> T) -> T {
+    f(FRAME_ALLOCATOR.lock().as_mut().expect("frame allocator not initialized"))
+}
+
+/// Like `with_frame_allocator`, but returns `None` instead of waiting if the allocator is locked.
+#[track_caller]
+pub fn try_with_frame_allocator<T>(f: impl FnOnce(&mut BitmapFrameAllocator) -> T) -> Option<T> {
+    Some(f(FRAME_ALLOCATOR.try_lock()?.as_mut().expect("frame allocator not initialized")))
+}
+
+/// Handle to the global frame allocator, for APIs that expect a `FrameAllocator` or `FrameDeallocator`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl<S: PageSize> FrameAllocator<S> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        with_frame_allocator(|allocator| allocator.allocate_frame())
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        with_frame_allocator(|allocator| unsafe { allocator.deallocate_frame(frame) })
+    }
+}
@@ -0,0 +1,170 @@
+// Page table inspection
+// Walks a complete page table hierarchy and reports what is mapped as ranges: consecutive pages that map
+// consecutive physical memory with the same flags are merged into one range. The flags of a range are the
+// effective ones - a page is only writable or user accessible if every level allows it, and not executable if any
+// level forbids it - so they can be checked against policies like "no page is both writable and executable".
+// The dump goes to serial in a stable format, so dumps from different runs can be diffed.
+
+use super::phys_to_virt;
+use core::fmt;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{OffsetPageTable, PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+/// Flags that the CPU updates on access, which would keep ranges from being merged.
+const ACCESS_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+
+/// Flags whose effective value depends on all levels.
+const HIERARCHICAL_FLAGS: PageTableFlags = PageTableFlags::WRITABLE
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// A range of virtual memory mapped to contiguous physical memory with the same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub phys: PhysAddr,
+    /// Effective flags. `HUGE_PAGE` is set if the range is mapped with 2 MiB or 1 GiB pages.
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    /// Last address in the range. The address just above it isn't canonical for a range at the top of the lower
+    /// half, and doesn't fit into a `u64` at the top of the address space.
+    pub fn last(&self) -> VirtAddr {
+        self.start + (self.size - 1)
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr <= self.last()
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags.contains(PageTableFlags::WRITABLE)
+    }
+
+    /// Without NXE in EFER the NO_EXECUTE bit is reserved, so every mapped page is executable.
+    pub fn is_executable(&self) -> bool {
+        !self.flags.contains(PageTableFlags::NO_EXECUTE)
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        write!(f, "{:#018x}-{:#018x} -> {:#014x} {:>10}K {}{}{}{}{}{}",
+            self.start.as_u64(), u128::from(self.last().as_u64()) + 1, self.phys.as_u64(), self.size / 1024,
+            flag(PageTableFlags::PRESENT, 'P'),
+            flag(PageTableFlags::WRITABLE, 'W'),
+            flag(PageTableFlags::USER_ACCESSIBLE, 'U'),
+            flag(PageTableFlags::NO_EXECUTE, 'N'),
+            flag(PageTableFlags::GLOBAL, 'G'),
+            flag(PageTableFlags::HUGE_PAGE, 'H'))
+    }
+}
+
+/// Calls `f` with every mapped range of the page table, in address order.
+pub fn for_each_range(page_table: &mut OffsetPageTable, f: impl FnMut(&MappedRange)) {
+    walk(page_table.level_4_table(), f);
+}
+
+/// Like `for_each_range`, for the active page table.
+pub fn for_each_active_range(f: impl FnMut(&MappedRange)) {
+    let p4 = unsafe { &*phys_to_virt(Cr3::read().0.start_address()).as_ptr::<PageTable>() };
+    walk(p4, f);
+}
+
+/// Prints the mapped ranges of the active page table to serial.
+pub fn dump_active() {
+    crate::serial_println!("{:<37}    {:<14} {:>11} flags", "virtual", "physical", "size");
+    for_each_active_range(|range| {
+        crate::serial_println!("{}", range);
+    });
+}
+
+/// Prints the ranges of the active page table that violate a policy to serial and returns how many there are.
+pub fn check_active(policy: &str, violates: impl Fn(&MappedRange) -> bool) -> usize {
+    let mut violations = 0;
+    for_each_active_range(|range| {
+        if violates(range) {
+            crate::serial_println!("{} violated: {}", policy, range);
+            violations += 1;
+        }
+    });
+    violations
+}
+
+/// Policy check for `check_active`: the range is both writable and executable.
+pub fn writable_and_executable(range: &MappedRange) -> bool {
+    range.is_writable() && range.is_executable()
+}
+
+fn walk(p4: &PageTable, mut f: impl FnMut(&MappedRange)) {
+    let mut current: Option<MappedRange> = None;
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    walk_table(p4, 4, 0, inherited, &mut |range| {
+        // Merge with the previous range if it continues it
+        if let Some(previous) = current.as_mut()
+            && previous.last().as_u64().checked_add(1) == Some(range.start.as_u64())
+            && previous.phys.as_u64() + previous.size == range.phys.as_u64()
+            && previous.flags == range.flags
+        {
+            previous.size += range.size;
+            return;
+        }
+        if let Some(previous) = current.replace(range) {
+            f(&previous);
+        }
+    });
+    if let Some(last) = current {
+        f(&last);
+    }
+}
+
+/// Visits the entries of a level `level` table that covers the virtual addresses from `base` on.
+/// `inherited` holds the hierarchical flags the higher levels allow.
+fn walk_table(table: &PageTable, level: u8, base: u64, inherited: PageTableFlags, f: &mut impl FnMut(MappedRange)) {
+    let entry_size = 1u64 << (12 + 9 * (u32::from(level) - 1));
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = VirtAddr::new_truncate(base + index as u64 * entry_size);
+        let effective = (inherited & flags & (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE))
+            | ((inherited | flags) & PageTableFlags::NO_EXECUTE);
+
+        let is_leaf = level == 1 || (level < 4 && flags.contains(PageTableFlags::HUGE_PAGE));
+        if is_leaf {
+            // In a level 1 entry the huge page bit selects the PAT entry instead
+            let own = if level == 1 { flags - PageTableFlags::HUGE_PAGE } else { flags };
+            let flags = (own - HIERARCHICAL_FLAGS - ACCESS_FLAGS) | effective;
+            f(MappedRange { start, size: entry_size, phys: entry.addr(), flags });
+        } else {
+            let lower = unsafe { &*phys_to_virt(entry.addr()).as_ptr::<PageTable>() };
+            walk_table(lower, level - 1, start.as_u64(), effective, f);
+        }
+    }
+}
+
+#[test_case]
+fn test_ranges_at_the_end_of_a_half() {
+    let range = |start, size| MappedRange {
+        start: VirtAddr::new(start),
+        size,
+        phys: PhysAddr::new(0),
+        flags: PageTableFlags::PRESENT,
+    };
+    let lower = range(0x7fff_ffe0_0000, 0x20_0000);
+    assert_eq!(lower.last(), VirtAddr::new(0x7fff_ffff_ffff));
+    assert!(lower.contains(VirtAddr::new(0x7fff_ffff_f000)));
+    assert!(!lower.contains(VirtAddr::new(0xffff_8000_0000_0000)));
+
+    let top = range(0xffff_ffff_c000_0000, 0x4000_0000);
+    assert_eq!(top.last(), VirtAddr::new(0xffff_ffff_ffff_ffff));
+    assert!(top.contains(VirtAddr::new(0xffff_ffff_ffff_f000)));
+    assert!(!top.contains(VirtAddr::new(0xffff_ffff_bfff_f000)));
+}
@@ -0,0 +1,195 @@
+// Memory mapped I/O
+// Device registers and memory - the local APIC, HPET, PCI BARs, framebuffers - must not be accessed through the
+// physical memory window, which is mapped write-back cacheable. `map_mmio` maps a physical range into a fresh
+// area of the kernel's address space instead, with the memory type the device needs, and `MmioRegion` unmaps it
+// again when dropped.
+// The memory type of a page is selected by its PCD and PWT flags, which index the page attribute table (PAT).
+// `init` changes PAT entry 1, the PWT-only combination, from write-through to write-combining, like Linux does;
+// the other entries keep their power-on defaults. The PAT bit itself isn't used, since it shares its position
+// with the huge page bit that the paging code checks.
+
+use super::vma::{Backing, VmaError, KERNEL_VMAS};
+use super::{frame::GlobalFrameAllocator, kernel_page_table};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::model_specific::Msr,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+const IA32_PAT_MSR: u32 = 0x277;
+
+// PAT memory types
+const PAT_WRITE_BACK: u64 = 0x06;
+const PAT_WRITE_THROUGH: u64 = 0x04;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+const PAT_UNCACHED_MINUS: u64 = 0x07;
+const PAT_UNCACHED: u64 = 0x00;
+
+/// PAT entries 0 to 7, selected by the PAT, PCD and PWT bits of a page table entry.
+const PAT_VALUE: u64 = PAT_WRITE_BACK
+    | PAT_WRITE_COMBINING << 8
+    | PAT_UNCACHED_MINUS << 16
+    | PAT_UNCACHED << 24
+    | PAT_WRITE_BACK << 32
+    | PAT_WRITE_THROUGH << 40
+    | PAT_UNCACHED_MINUS << 48
+    | PAT_UNCACHED << 56;
+
+static PAT_PROGRAMMED: AtomicBool = AtomicBool::new(false);
+
+/// Memory type of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Normal cacheable memory
+    WriteBack,
+    /// Uncached, but writes may be combined into bursts. For framebuffers and similar write-only buffers.
+    WriteCombining,
+    /// Every access goes to the device, in program order. For device registers.
+    Uncached,
+}
+
+impl CachePolicy {
+    /// Page table flags that select the policy.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CachePolicy::WriteBack => PageTableFlags::empty(),
+            // Without the PAT, PWT alone selects write-through, so fall back to uncached
+            CachePolicy::WriteCombining if !PAT_PROGRAMMED.load(Ordering::Relaxed) => CachePolicy::Uncached.flags(),
+            CachePolicy::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CachePolicy::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Programs the page attribute table, if the CPU has one. Must be called before the first write-combining mapping.
+pub fn init() {
+    let pat_supported = core::arch::x86_64::__cpuid(1).edx & (1 << 16) != 0;
+    if !pat_supported {
+        return;
+    }
+    // The caches and TLBs may hold lines and translations of the old memory types
+    interrupts::without_interrupts(|| unsafe {
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        Msr::new(IA32_PAT_MSR).write(PAT_VALUE);
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+    });
+    PAT_PROGRAMMED.store(true, Ordering::Relaxed);
+}
+
+/// Maps `len` bytes of device memory at `phys` with the given cache policy into the kernel's address space.
+/// Requires the heap, the frame allocator and the VMA manager.
+///
+/// # Safety
+/// The range must belong to a device, not to RAM the kernel uses, and must not be mapped with a different
+/// memory type anywhere else.
+pub unsafe fn map_mmio(phys: PhysAddr, len: u64, policy: CachePolicy) -> Result<MmioRegion, VmaError> {
+    assert!(len > 0, "mapping an empty MMIO range");
+    let first_page = phys.align_down(Size4KiB::SIZE);
+    let end = phys.as_u64().checked_add(len)
+        .and_then(|end| end.checked_next_multiple_of(Size4KiB::SIZE))
+        .and_then(|end| PhysAddr::try_new(end).ok())
+        .ok_or(VmaError::OutOfSpace)?;
+    let size = end - first_page;
+
+    let mut vmas = KERNEL_VMAS.lock();
+    let start = vmas.reserve("mmio", size)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut mapper = unsafe { kernel_page_table() };
+    if let Err(error) = vmas.map(start, Backing::Mmio(first_page, policy), flags, &mut mapper, &mut GlobalFrameAllocator) {
+        vmas.unmap(start, &mut mapper, &mut GlobalFrameAllocator)?;
+        return Err(error);
+    }
+    Ok(MmioRegion { area: start, base: start + (phys - first_page), phys, len })
+}
+
+/// A mapped range of device memory. Unmapped when dropped.
+#[derive(Debug)]
+pub struct MmioRegion {
+    /// Start of the VMA, the page containing `base`
+    area: VirtAddr,
+    base: VirtAddr,
+    phys: PhysAddr,
+    len: u64,
+}
+
+impl MmioRegion {
+    /// Virtual address of the first byte of the range.
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the register of type `T` at `offset` bytes into the range.
+    /// Panics if it doesn't fit into the range or is misaligned.
+    pub fn register<T: Copy>(&self, offset: usize) -> &Volatile<T> {
+        let addr = self.base + offset as u64;
+        assert!(offset as u64 + core::mem::size_of::<T>() as u64 <= self.len, "register outside of MMIO range");
+        assert!(addr.is_aligned(core::mem::align_of::<T>() as u64), "misaligned MMIO register");
+        unsafe { &*addr.as_ptr::<Volatile<T>>() }
+    }
+
+    /// Returns the range as a block of registers, typically a `#[repr(C)]` struct of `Volatile` fields.
+    ///
+    /// # Safety
+    /// `T` must describe the device's register layout, and only consist of `Volatile` fields and padding.
+    pub unsafe fn registers<T>(&self) -> &T {
+        assert!(core::mem::size_of::<T>() as u64 <= self.len, "register block larger than MMIO range");
+        assert!(self.base.is_aligned(core::mem::align_of::<T>() as u64), "misaligned MMIO register block");
+        unsafe { &*self.base.as_ptr::<T>() }
+    }
+
+    /// Reads the value of type `T` at `offset` bytes into the range.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        self.register(offset).read()
+    }
+
+    /// Writes a value of type `T` at `offset` bytes into the range.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        self.register(offset).write(value)
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let mut mapper = unsafe { kernel_page_table() };
+        KERNEL_VMAS.lock().unmap(self.area, &mut mapper, &mut GlobalFrameAllocator)
+            .expect("unmapping MMIO region failed");
+    }
+}
+
+/// A device register. Every access is a single volatile read or write of the whole value.
+#[repr(transparent)]
+pub struct Volatile<T: Copy>(UnsafeCell<T>);
+
+// Registers are accessed through shared references; the device decides what concurrent accesses do
+unsafe impl<T: Copy> Sync for Volatile<T> {}
+
+impl<T: Copy> Volatile<T> {
+    pub fn read(&self) -> T {
+        unsafe { self.0.get().read_volatile() }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { self.0.get().write_volatile(value) }
+    }
+
+    /// Reads the register, applies `f` and writes the result back.
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()))
+    }
+}
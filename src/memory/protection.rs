@@ -0,0 +1,135 @@
+// Kernel memory protection
+// Enforces W^X for the kernel half of the kernel's page table: no page is both writable and executable.
+// The kernel image is mapped from its ELF program headers, which the linker places in the first loadable segment
+// where `__ehdr_start` points to: text is read-only and executable, rodata read-only and no-execute, data and bss
+// writable and no-execute. The RELRO part of the data - `.data.rel.ro` and the GOT - is only written by the linker,
+// so it becomes read-only as well.
+// Every other page - the heap, the stacks, the physical memory window, the boot info and the bootloader's identity
+// mapping of low memory - is made no-execute. Later mappings have to ask for NO_EXECUTE themselves, which the heap,
+// stack and MMIO code do.
+
+use super::{address_space::USER_P4_ENTRIES, phys_to_virt, KERNEL_P4_FRAME};
+use core::sync::atomic::Ordering;
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{PageSize, PageTable, PageTableFlags, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+// ELF program header types and flags
+const PT_LOAD: u32 = 1;
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+unsafe extern "C" {
+    /// ELF header of the kernel, defined by the linker.
+    static __ehdr_start: u8;
+}
+
+/// ELF64 program header.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    virt_addr: u64,
+    phys_addr: u64,
+    file_size: u64,
+    mem_size: u64,
+    align: u64,
+}
+
+impl ProgramHeader {
+    fn end(&self) -> u64 {
+        self.virt_addr + self.mem_size
+    }
+
+    /// Returns the part of the segment within the page at `page`, if any.
+    fn part_in(&self, page: u64) -> Option<(u64, u64)> {
+        let start = self.virt_addr.max(page);
+        let end = self.end().min(page + Size4KiB::SIZE);
+        (start < end).then_some((start, end))
+    }
+}
+
+/// Returns the program headers of the kernel image.
+fn program_headers() -> &'static [ProgramHeader] {
+    unsafe {
+        let header = &raw const __ehdr_start;
+        let phoff = header.add(32).cast::<u64>().read_unaligned();
+        let phnum = header.add(56).cast::<u16>().read_unaligned();
+        core::slice::from_raw_parts(header.add(phoff as usize).cast::<ProgramHeader>(), usize::from(phnum))
+    }
+}
+
+/// Returns the flags the kernel image page at `page` should have, or `None` if it isn't part of the image.
+/// A page shared by two segments gets the permissions of both, and stays writable unless all its writable data
+/// is RELRO.
+fn image_page_flags(page: u64) -> Option<PageTableFlags> {
+    let headers = program_headers();
+    let is_relro = |(start, end)| headers.iter()
+        .any(|header| header.kind == PT_GNU_RELRO && header.virt_addr <= start && end <= header.end());
+
+    let mut in_image = false;
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    for header in headers.iter().filter(|header| header.kind == PT_LOAD) {
+        let Some(part) = header.part_in(page) else {
+            continue;
+        };
+        in_image = true;
+        if header.flags & PF_W != 0 && !is_relro(part) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if header.flags & PF_X != 0 {
+            flags -= PageTableFlags::NO_EXECUTE;
+        }
+    }
+    in_image.then_some(flags)
+}
+
+/// Remaps the kernel image by its ELF segments and makes every other kernel page no-execute. Call once the kernel
+/// no longer relies on the bootloader's mappings being executable, which it never does after `memory::init`.
+pub fn init() {
+    let p4_frame = PhysAddr::new(KERNEL_P4_FRAME.load(Ordering::Relaxed));
+    assert!(!p4_frame.is_null(), "memory not initialized");
+    let p4 = unsafe { &mut *phys_to_virt(p4_frame).as_mut_ptr::<PageTable>() };
+    for (index, entry) in p4.iter().enumerate() {
+        if USER_P4_ENTRIES.contains(&index) || !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let table = unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() };
+        let base = VirtAddr::new_truncate(index as u64 * (Size4KiB::SIZE << 27)).as_u64();
+        protect_table(table, 3, base);
+    }
+    tlb::flush_all();
+}
+
+/// Applies the protection to the leaves of a level `level` table covering the virtual addresses from `base` on.
+fn protect_table(table: &mut PageTable, level: u8, base: u64) {
+    let entry_size = Size4KiB::SIZE << (9 * (u32::from(level) - 1));
+    for (index, entry) in table.iter_mut().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = base + index as u64 * entry_size;
+        if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            let lower = unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() };
+            protect_table(lower, level - 1, start);
+            continue;
+        }
+
+        // The kernel image consists of 4 KiB pages, huge pages are never part of it
+        let image_flags = if level == 1 { image_page_flags(start) } else { None };
+        let new_flags = match image_flags {
+            Some(image_flags) => {
+                let permissions = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+                (flags - permissions) | image_flags
+            }
+            None => flags | PageTableFlags::NO_EXECUTE,
+        };
+        entry.set_flags(new_flags);
+    }
+}
@@ -0,0 +1,247 @@
+// Guard-paged stacks
+// Stacks are carved out of a dedicated virtual region. Every stack is a range of mapped pages with an unmapped
+// guard page directly below it, so overflowing a stack page faults instead of silently corrupting the memory
+// next to it.
+// New stacks are painted with a known pattern. The lowest word that no longer holds the pattern marks the deepest
+// point the stack ever reached, which `usage` reports for every tracked stack - including the boot stack, whose
+// location is pinned in the bootloader configuration in Cargo.toml.
+
+use crate::sync::IrqMutex;
+use x86_64::{
+    structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// Virtual region reserved for stacks.
+pub const STACK_REGION_START: u64 = 0x_5555_0000_0000;
+pub const STACK_REGION_SIZE: u64 = 1 << 30;        // 1 GiB
+
+/// Boot stack set up by the bootloader. Must match `kernel-stack-address` and `kernel-stack-size` in Cargo.toml.
+const BOOT_STACK_GUARD_PAGE: u64 = 0x_5554_0000_0000;
+const BOOT_STACK_PAGES: u64 = 512;
+
+/// Word written over unused stack memory.
+const STACK_PAINT: u64 = 0x57ac_57ac_57ac_57ac;
+
+/// Part of the boot stack below the current stack pointer that is left unpainted, for the painting code's callees.
+const BOOT_STACK_PAINT_MARGIN: u64 = 4096;
+
+/// Maximum number of stacks whose usage is tracked.
+const MAX_TRACKED_STACKS: usize = 32;
+
+/// Allocator for the kernel's stacks. The stacks handed out are never freed.
+pub static STACK_ALLOCATOR: IrqMutex<StackAllocator> =
+    IrqMutex::new(StackAllocator::new(VirtAddr::new_truncate(STACK_REGION_START), STACK_REGION_SIZE));
+
+#[derive(Debug)]
+pub enum StackError {
+    /// The stack region has no room left for a stack of the requested size
+    RegionExhausted,
+    /// Mapping the stack pages failed
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for StackError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        StackError::Map(error)
+    }
+}
+
+/// Stacks whose high-water mark is tracked, with their names.
+static TRACKED_STACKS: IrqMutex<[Option<(&'static str, StackBounds)>; MAX_TRACKED_STACKS]> =
+    IrqMutex::new([None; MAX_TRACKED_STACKS]);
+
+/// Maximum usage of a tracked stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackUsage {
+    pub name: &'static str,
+    pub size: u64,
+    pub max_used: u64,
+}
+
+/// The mapped part of a stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBounds {
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl StackBounds {
+    /// Lowest mapped address of the stack.
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Address just above the stack - the initial stack pointer.
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    /// The unmapped page below the stack.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.start - 1u64)
+    }
+
+    /// Size of the stack in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Returns the most bytes the stack has ever used since it was painted.
+    pub fn max_used(&self) -> u64 {
+        let mut address = self.start;
+        while address < self.end && unsafe { address.as_ptr::<u64>().read_volatile() } == STACK_PAINT {
+            address += 8u64;
+        }
+        self.end - address
+    }
+
+    /// Fills the stack from its start up to `limit` with the paint pattern.
+    ///
+    /// # Safety
+    /// The painted part must not be in use.
+    pub unsafe fn paint(&self, limit: VirtAddr) {
+        let mut address = self.start;
+        while address < limit.min(self.end) {
+            unsafe { address.as_mut_ptr::<u64>().write_volatile(STACK_PAINT) };
+            address += 8u64;
+        }
+    }
+}
+
+/// Hands out guard-paged stacks from a virtual address region.
+pub struct StackAllocator {
+    next: VirtAddr,
+    end: VirtAddr,
+}
+
+impl StackAllocator {
+    /// Creates an allocator for the given region. The region start must be page aligned.
+    pub const fn new(start: VirtAddr, size: u64) -> Self {
+        StackAllocator {
+            next: start,
+            end: VirtAddr::new_truncate(start.as_u64() + size),
+        }
+    }
+
+    /// Maps a stack of `pages` pages below a fresh guard page.
+    pub fn allocate(
+        &mut self,
+        pages: u64,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<StackBounds, StackError> {
+        assert!(pages > 0, "stacks need at least one page");
+
+        let guard_page = Page::<Size4KiB>::containing_address(self.next);
+        let stack_start = guard_page + 1;
+        let stack_end = stack_start + pages;
+        if stack_end.start_address() > self.end {
+            return Err(StackError::RegionExhausted);
+        }
+
+        for page in Page::range(stack_start, stack_end) {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            unsafe {
+                mapper.map_to(page, frame, flags, frame_allocator)?.flush()
+            };
+        }
+
+        // The next stack's guard page starts right above this stack
+        self.next = stack_end.start_address();
+        let bounds = StackBounds {
+            start: stack_start.start_address(),
+            end: stack_end.start_address(),
+        };
+        unsafe { bounds.paint(bounds.end) };
+        Ok(bounds)
+    }
+}
+
+/// Maps a stack of `pages` pages from the global stack allocator and tracks its usage under `name`.
+pub fn allocate_stack(
+    name: &'static str,
+    pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<StackBounds, StackError> {
+    let stack = STACK_ALLOCATOR.lock().allocate(pages, mapper, frame_allocator)?;
+    track(name, stack);
+    Ok(stack)
+}
+
+/// Paints the unused part of the boot stack and starts tracking its usage. Does nothing if the kernel isn't
+/// running on the boot stack configured in Cargo.toml.
+pub fn track_boot_stack() {
+    let Some((stack, _)) = paint_boot_stack() else {
+        crate::serial_println!("WARNING: not running on the configured boot stack, its usage is not tracked");
+        return;
+    };
+    track("boot", stack);
+}
+
+/// Paints the boot stack below the current stack pointer again, so its usage afterwards shows how deep the code
+/// from here on goes rather than the deepest point since boot. Returns the bytes in use when it was called, or
+/// `None` if the kernel isn't running on the boot stack. The unpainted margin below the stack pointer counts as
+/// used, so the depth measured is rounded up to at least `BOOT_STACK_PAINT_MARGIN` bytes.
+pub fn repaint_boot_stack() -> Option<u64> {
+    paint_boot_stack().map(|(stack, rsp)| stack.end - rsp)
+}
+
+/// Paints the boot stack up to `BOOT_STACK_PAINT_MARGIN` bytes below the stack pointer. Returns the stack and the
+/// stack pointer, or `None` if the kernel isn't running on the boot stack.
+fn paint_boot_stack() -> Option<(StackBounds, VirtAddr)> {
+    let start = VirtAddr::new(BOOT_STACK_GUARD_PAGE + 4096);
+    let stack = StackBounds { start, end: start + BOOT_STACK_PAGES * 4096 };
+
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    let rsp = VirtAddr::new(rsp);
+    if rsp <= stack.start || rsp > stack.end {
+        return None;
+    }
+    unsafe { stack.paint(rsp - BOOT_STACK_PAINT_MARGIN) };
+    Some((stack, rsp))
+}
+
+/// Starts tracking the usage of a painted stack. Returns false if too many stacks are tracked already.
+pub fn track(name: &'static str, stack: StackBounds) -> bool {
+    let mut stacks = TRACKED_STACKS.lock();
+    match stacks.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some((name, stack));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns the usage of the tracked stack with the given name.
+pub fn usage(name: &str) -> Option<StackUsage> {
+    let stacks = TRACKED_STACKS.lock();
+    stacks
+        .iter()
+        .flatten()
+        .find(|(stack_name, _)| *stack_name == name)
+        .map(|&(name, stack)| StackUsage { name, size: stack.size(), max_used: stack.max_used() })
+}
+
+/// Calls `f` with the usage of every tracked stack.
+pub fn for_each_usage(mut f: impl FnMut(StackUsage)) {
+    let stacks = *TRACKED_STACKS.lock();
+    for (name, stack) in stacks.into_iter().flatten() {
+        f(StackUsage { name, size: stack.size(), max_used: stack.max_used() });
+    }
+}
+
+/// Prints the usage of every tracked stack to serial.
+pub fn print_usage() {
+    crate::serial_println!("{:<16} {:>10} {:>10} {:>6}", "stack", "size", "max used", "%");
+    for_each_usage(|usage| {
+        crate::serial_println!("{:<16} {:>10} {:>10} {:>6}",
+            usage.name, usage.size, usage.max_used, usage.max_used * 100 / usage.size);
+    });
+}
@@ -0,0 +1,203 @@
+// Memory statistics
+// Summarizes how the kernel uses physical memory: the bootloader's memory map by region type, the frame
+// allocator's free frames and the frames it has handed out and freed since boot, and the frames taken up by the
+// kernel's page tables. `MemoryStats::collect` takes a snapshot, and `print` writes it to serial as a report.
+// Comparing the counters of two snapshots catches frame leaks: a piece of code that frees everything it allocates
+// leaves `allocated - freed` unchanged.
+
+use super::frame::{try_with_frame_allocator, with_frame_allocator, BitmapFrameAllocator};
+use super::{phys_to_virt, KERNEL_P4_FRAME};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::Ordering;
+use x86_64::{
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr,
+};
+
+const FRAME_SIZE: usize = 4096;
+
+/// Region types of the memory map, in the order they are reported.
+const REGION_TYPES: [MemoryRegionType; 14] = [
+    MemoryRegionType::Usable,
+    MemoryRegionType::InUse,
+    MemoryRegionType::Reserved,
+    MemoryRegionType::AcpiReclaimable,
+    MemoryRegionType::AcpiNvs,
+    MemoryRegionType::BadMemory,
+    MemoryRegionType::Kernel,
+    MemoryRegionType::KernelStack,
+    MemoryRegionType::PageTable,
+    MemoryRegionType::Bootloader,
+    MemoryRegionType::FrameZero,
+    MemoryRegionType::Empty,
+    MemoryRegionType::BootInfo,
+    MemoryRegionType::Package,
+];
+
+/// A snapshot of the kernel's physical memory usage. All sizes are in 4 KiB frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Frames of each type in `REGION_TYPES`, as the memory map reports them
+    by_type: [usize; REGION_TYPES.len()],
+    /// Frames in the memory map
+    pub total: usize,
+    /// Frames the frame allocator manages
+    pub usable: usize,
+    /// Frames holding the kernel image and the boot stack
+    pub kernel: usize,
+    /// Frames the bootloader used for the boot: its own code, the boot info and the initial page tables
+    pub boot: usize,
+    /// Frames of firmware, ACPI, bad and other memory the kernel must not touch
+    pub reserved: usize,
+    /// Usable frames that are currently free
+    pub free: usize,
+    /// Frames handed out since boot
+    pub allocated: u64,
+    /// Frames freed since boot
+    pub freed: u64,
+    /// Frames holding the tables of the kernel's page table, including the level 4 table
+    pub page_table_frames: usize,
+}
+
+impl MemoryStats {
+    /// Takes a snapshot. Requires the frame allocator.
+    pub fn collect() -> Self {
+        Self::from_allocator(with_frame_allocator(|allocator| AllocatorCounts::read(allocator)))
+    }
+
+    /// Like `collect`, but returns `None` if the frame allocator is locked.
+    pub fn try_collect() -> Option<Self> {
+        try_with_frame_allocator(|allocator| AllocatorCounts::read(allocator)).map(Self::from_allocator)
+    }
+
+    fn from_allocator(counts: AllocatorCounts) -> Self {
+        let AllocatorCounts { memory_map, usable, free, allocated, freed } = counts;
+
+        let by_type = REGION_TYPES.map(|region_type| frames_of_type(memory_map, region_type));
+        let frames = |types: &[MemoryRegionType]| REGION_TYPES.iter().zip(&by_type)
+            .filter(|(region_type, _)| types.contains(region_type))
+            .map(|(_, &frames)| frames)
+            .sum::<usize>();
+
+        let total = memory_map.iter().map(|region| region_frames(region.range.start_frame_number,
+            region.range.end_frame_number)).sum();
+        let kernel = frames(&[MemoryRegionType::Kernel, MemoryRegionType::KernelStack]);
+        let boot = frames(&[MemoryRegionType::InUse, MemoryRegionType::PageTable, MemoryRegionType::Bootloader,
+            MemoryRegionType::FrameZero, MemoryRegionType::BootInfo, MemoryRegionType::Package]);
+        MemoryStats {
+            by_type,
+            total,
+            usable,
+            kernel,
+            boot,
+            reserved: total - usable - kernel - boot,
+            free,
+            allocated,
+            freed,
+            page_table_frames: kernel_page_table_frames(),
+        }
+    }
+
+    /// Usable frames that are currently allocated, including the frame allocator's own bitmap.
+    pub fn in_use(&self) -> usize {
+        self.usable - self.free
+    }
+
+    /// Frames of the given type in the memory map.
+    pub fn frames_of_type(&self, region_type: MemoryRegionType) -> usize {
+        REGION_TYPES.iter().position(|&t| t == region_type).map_or(0, |index| self.by_type[index])
+    }
+
+    /// Prints the report to serial.
+    pub fn print(&self) {
+        let line = |name: &str, frames: usize| {
+            crate::serial_println!("  {:<18} {:>9} frames {:>9} KiB", name, frames, frames * FRAME_SIZE / 1024);
+        };
+        crate::serial_println!("memory map:");
+        for (region_type, &frames) in REGION_TYPES.iter().zip(&self.by_type) {
+            if frames > 0 {
+                line(region_type_name(*region_type), frames);
+            }
+        }
+        crate::serial_println!("summary:");
+        line("total", self.total);
+        line("usable", self.usable);
+        line("kernel", self.kernel);
+        line("boot", self.boot);
+        line("reserved", self.reserved);
+        line("in use", self.in_use());
+        line("free", self.free);
+        line("page tables", self.page_table_frames);
+        crate::serial_println!("  {:<18} {:>9} frames", "allocated", self.allocated);
+        crate::serial_println!("  {:<18} {:>9} frames", "freed", self.freed);
+    }
+}
+
+/// The frame allocator's part of a snapshot, read while it is locked.
+struct AllocatorCounts {
+    memory_map: &'static MemoryMap,
+    usable: usize,
+    free: usize,
+    allocated: u64,
+    freed: u64,
+}
+
+impl AllocatorCounts {
+    fn read(allocator: &BitmapFrameAllocator) -> Self {
+        AllocatorCounts {
+            memory_map: allocator.memory_map(),
+            usable: allocator.usable_frames(),
+            free: allocator.free_frames(),
+            allocated: allocator.allocated_total(),
+            freed: allocator.freed_total(),
+        }
+    }
+}
+
+fn region_type_name(region_type: MemoryRegionType) -> &'static str {
+    match region_type {
+        MemoryRegionType::Usable => "usable",
+        MemoryRegionType::InUse => "in use",
+        MemoryRegionType::Reserved => "reserved",
+        MemoryRegionType::AcpiReclaimable => "ACPI reclaimable",
+        MemoryRegionType::AcpiNvs => "ACPI NVS",
+        MemoryRegionType::BadMemory => "bad memory",
+        MemoryRegionType::Kernel => "kernel",
+        MemoryRegionType::KernelStack => "kernel stack",
+        MemoryRegionType::PageTable => "page tables",
+        MemoryRegionType::Bootloader => "bootloader",
+        MemoryRegionType::FrameZero => "frame zero",
+        MemoryRegionType::Empty => "empty",
+        MemoryRegionType::BootInfo => "boot info",
+        MemoryRegionType::Package => "package",
+        _ => "other",
+    }
+}
+
+fn region_frames(start: u64, end: u64) -> usize {
+    end.saturating_sub(start) as usize
+}
+
+fn frames_of_type(memory_map: &MemoryMap, region_type: MemoryRegionType) -> usize {
+    memory_map.iter()
+        .filter(|region| region.region_type == region_type)
+        .map(|region| region_frames(region.range.start_frame_number, region.range.end_frame_number))
+        .sum()
+}
+
+/// Counts the tables reachable from the kernel's level 4 table. Tables of other address spaces' user halves
+/// aren't included.
+fn kernel_page_table_frames() -> usize {
+    let p4_frame = PhysAddr::new(KERNEL_P4_FRAME.load(Ordering::Relaxed));
+    assert!(!p4_frame.is_null(), "memory not initialized");
+    count_tables(p4_frame, 4)
+}
+
+fn count_tables(table: PhysAddr, level: u8) -> usize {
+    let table = unsafe { &*phys_to_virt(table).as_ptr::<PageTable>() };
+    1 + table.iter()
+        .filter(|entry| level > 1 && entry.flags().contains(PageTableFlags::PRESENT)
+            && !entry.flags().contains(PageTableFlags::HUGE_PAGE))
+        .map(|entry| count_tables(entry.addr(), level - 1))
+        .sum::<usize>()
+}
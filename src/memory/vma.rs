@@ -0,0 +1,424 @@
+// Virtual memory areas
+// The kernel's virtual address space is divided into areas, each a page aligned range with a name, the
+// permissions its pages are mapped with and what backs them. Areas are kept in a BTreeMap keyed by their start
+// address and never overlap. An area is first reserved - either at a fixed address, for the regions the kernel
+// has always placed by hand, or anywhere in the dynamic region - and then mapped, re-protected and unmapped as a
+// whole. Unmapping an area also releases its reservation.
+// `init` records the areas that exist before the manager can allocate (the heap, the stack region and the
+// physical memory window), so `print_layout` shows the complete picture. Recorded areas are pinned: they belong to
+// the code that set them up, so the manager refuses to map, unmap or re-protect them.
+
+use super::{address_space, frame, map_contiguous, map_physical_range, phys_to_virt, PHYSICAL_MEMORY_OFFSET};
+use super::mmio::CachePolicy;
+use crate::allocator::{HEAP_SIZE, HEAP_START};
+use crate::memory::stack::{STACK_REGION_SIZE, STACK_REGION_START};
+use crate::sync::IrqMutex;
+use alloc::collections::BTreeMap;
+use core::fmt;
+use core::sync::atomic::Ordering;
+use x86_64::{
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame,
+        Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Region in which `reserve` places areas. It lies in the higher half, which the kernel doesn't use otherwise.
+pub const DYNAMIC_REGION_START: u64 = 0x_ffff_c000_0000_0000;
+pub const DYNAMIC_REGION_SIZE: u64 = 1 << 40;       // 1 TiB
+
+/// The kernel's virtual memory areas.
+pub static KERNEL_VMAS: IrqMutex<VmaManager> =
+    IrqMutex::new(VmaManager::new(VirtAddr::new_truncate(DYNAMIC_REGION_START), DYNAMIC_REGION_SIZE));
+
+/// What the pages of an area are mapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Reserved address space with nothing mapped
+    Reserved,
+    /// Zeroed frames from the frame allocator, freed on unmap
+    Anonymous,
+    /// Device memory starting at the given physical address, mapped with the given memory type
+    Mmio(PhysAddr, CachePolicy),
+    /// Physical memory starting at the given address, mapped with the largest pages possible
+    Physical(PhysAddr),
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    /// Address or size is not page aligned, or the size is zero
+    Unaligned,
+    /// The range overlaps an existing area
+    Overlap,
+    /// The range runs past the end of the address space, or the dynamic region has no gap large enough
+    OutOfSpace,
+    /// No area starts at the given address
+    NotFound,
+    /// `map` was called on an area that is mapped already
+    AlreadyMapped,
+    /// The area is pinned and can't be changed through the manager
+    Pinned,
+    Map(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+    Protect(FlagUpdateError),
+}
+
+impl From<MapToError<Size4KiB>> for VmaError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        VmaError::Map(error)
+    }
+}
+
+impl From<UnmapError> for VmaError {
+    fn from(error: UnmapError) -> Self {
+        VmaError::Unmap(error)
+    }
+}
+
+impl From<FlagUpdateError> for VmaError {
+    fn from(error: FlagUpdateError) -> Self {
+        VmaError::Protect(error)
+    }
+}
+
+/// A virtual memory area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    /// Permissions the pages are mapped with
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+    /// Set up outside the manager, which leaves it alone
+    pub pinned: bool,
+}
+
+impl Vma {
+    /// An area of reserved address space.
+    fn reserved(name: &'static str, start: VirtAddr, size: u64) -> Self {
+        Vma { name, start, size, flags: PageTableFlags::empty(), backing: Backing::Reserved, pinned: false }
+    }
+
+    /// Address just above the area.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Flags written to the page table entries, including the ones implied by the backing.
+    fn page_flags(&self) -> PageTableFlags {
+        let flags = self.flags | PageTableFlags::PRESENT;
+        match self.backing {
+            Backing::Mmio(_, policy) => flags | policy.flags(),
+            _ => flags,
+        }
+    }
+}
+
+impl fmt::Display for Vma {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        write!(f, "{:#018x}-{:#018x} {:>10} {}{}{} ",
+            self.start.as_u64(), self.end().as_u64(), self.size / 1024,
+            access(PageTableFlags::WRITABLE, 'w'),
+            access(PageTableFlags::USER_ACCESSIBLE, 'u'),
+            if self.flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' })?;
+        match self.backing {
+            Backing::Reserved => write!(f, "{:<20}", "reserved")?,
+            Backing::Anonymous => write!(f, "{:<20}", "anonymous")?,
+            Backing::Mmio(phys, CachePolicy::WriteCombining) => write!(f, "mmio-wc {:<#12x}", phys.as_u64())?,
+            Backing::Mmio(phys, _) => write!(f, "mmio {:<#15x}", phys.as_u64())?,
+            Backing::Physical(phys) => write!(f, "phys {:<#15x}", phys.as_u64())?,
+        }
+        write!(f, " {}{}", self.name, if self.pinned { " (pinned)" } else { "" })
+    }
+}
+
+/// Tracks the areas of an address space and maps them in a page table.
+pub struct VmaManager {
+    areas: BTreeMap<u64, Vma>,
+    dynamic_start: VirtAddr,
+    dynamic_end: VirtAddr,
+}
+
+impl VmaManager {
+    /// Creates a manager without areas that places reservations without a fixed address in the given region.
+    pub const fn new(dynamic_start: VirtAddr, dynamic_size: u64) -> Self {
+        VmaManager {
+            areas: BTreeMap::new(),
+            dynamic_start,
+            dynamic_end: VirtAddr::new_truncate(dynamic_start.as_u64() + dynamic_size),
+        }
+    }
+
+    /// Reserves `size` bytes anywhere in the dynamic region and returns the start address. A guard page is left
+    /// free below every reservation.
+    pub fn reserve(&mut self, name: &'static str, size: u64) -> Result<VirtAddr, VmaError> {
+        check_aligned(self.dynamic_start, size)?;
+        let mut candidate = checked_end(self.dynamic_start, Size4KiB::SIZE)?;
+        for area in self.areas.range(self.dynamic_start.as_u64()..self.dynamic_end.as_u64()).map(|(_, area)| area) {
+            if checked_end(candidate, size)? <= area.start {
+                break;
+            }
+            candidate = candidate.max(checked_end(area.end(), Size4KiB::SIZE)?);
+        }
+        if checked_end(candidate, size)? > self.dynamic_end {
+            return Err(VmaError::OutOfSpace);
+        }
+        self.insert(Vma::reserved(name, candidate, size))?;
+        Ok(candidate)
+    }
+
+    /// Reserves `size` bytes at `start`.
+    pub fn reserve_at(&mut self, name: &'static str, start: VirtAddr, size: u64) -> Result<(), VmaError> {
+        self.insert(Vma::reserved(name, start, size))
+    }
+
+    /// Records an area that has been set up without the manager, like the heap. The area is pinned.
+    pub fn record(&mut self, area: Vma) -> Result<(), VmaError> {
+        self.insert(Vma { pinned: true, ..area })
+    }
+
+    /// Maps the reserved area starting at `start` with the given backing and permissions. If mapping fails part
+    /// way, the pages mapped so far are unmapped again and the area stays reserved.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        backing: Backing,
+        flags: PageTableFlags,
+        mapper: &mut OffsetPageTable<'static>,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<(), VmaError> {
+        let area = self.get_mut(start)?;
+        if area.backing != Backing::Reserved {
+            return Err(VmaError::AlreadyMapped);
+        }
+
+        // Pages mapped behind the manager's back would be unmapped by a failed `map` or by `unmap`
+        for_each_mapping(area, mapper, |_, _, _| Err(VmaError::AlreadyMapped))?;
+
+        let mapped = Vma { flags, backing, ..*area };
+        if let Err(error) = map_area(&mapped, mapper, frame_allocator) {
+            unmap_area(&mapped, mapper, frame_allocator)?;
+            return Err(error);
+        }
+        *area = mapped;
+        Ok(())
+    }
+
+    /// Unmaps the area starting at `start` and releases it. Anonymous frames are returned to `frame_deallocator`.
+    pub fn unmap(
+        &mut self,
+        start: VirtAddr,
+        mapper: &mut OffsetPageTable<'static>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<Vma, VmaError> {
+        let area = *self.get_mut(start)?;
+        unmap_area(&area, mapper, frame_deallocator)?;
+        self.areas.remove(&start.as_u64());
+        Ok(area)
+    }
+
+    /// Changes the permissions of the area starting at `start`.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        flags: PageTableFlags,
+        mapper: &mut OffsetPageTable<'static>,
+    ) -> Result<(), VmaError> {
+        let area = self.get_mut(start)?;
+        let updated = Vma { flags, ..*area };
+        if area.backing != Backing::Reserved {
+            for_each_mapping(&updated, mapper, |mapper, addr, frame| {
+                let flags = updated.page_flags();
+                unsafe {
+                    match frame {
+                        MappedFrame::Size4KiB(_) => {
+                            mapper.update_flags(Page::<Size4KiB>::containing_address(addr), flags)?.flush()
+                        }
+                        MappedFrame::Size2MiB(_) => {
+                            mapper.update_flags(Page::<Size2MiB>::containing_address(addr), flags)?.flush()
+                        }
+                        MappedFrame::Size1GiB(_) => {
+                            mapper.update_flags(Page::<Size1GiB>::containing_address(addr), flags)?.flush()
+                        }
+                    }
+                }
+                Ok::<(), VmaError>(())
+            })?;
+            address_space::kernel_mappings_changed();
+        }
+        *area = updated;
+        Ok(())
+    }
+
+    /// Returns the area containing `addr`.
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas.range(..=addr.as_u64()).next_back().map(|(_, area)| area).filter(|area| area.contains(addr))
+    }
+
+    /// Returns the areas in address order.
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// Prints the areas in address order to serial.
+    pub fn print_layout(&self) {
+        crate::serial_println!("{:<37} {:>10} {:<3} {:<20} {}", "range", "KiB", "prm", "backing", "name");
+        for area in self.iter() {
+            crate::serial_println!("{}", area);
+        }
+    }
+
+    /// Returns the area starting at `start`, unless it is pinned.
+    fn get_mut(&mut self, start: VirtAddr) -> Result<&mut Vma, VmaError> {
+        let area = self.areas.get_mut(&start.as_u64()).ok_or(VmaError::NotFound)?;
+        if area.pinned {
+            return Err(VmaError::Pinned);
+        }
+        Ok(area)
+    }
+
+    fn insert(&mut self, area: Vma) -> Result<(), VmaError> {
+        check_aligned(area.start, area.size)?;
+        // Stored areas end at a canonical address, so `Vma::end` can't fail
+        let end = checked_end(area.start, area.size)?;
+        let overlaps_previous = self.areas.range(..end.as_u64()).next_back()
+            .is_some_and(|(_, previous)| previous.end() > area.start);
+        if overlaps_previous {
+            return Err(VmaError::Overlap);
+        }
+        self.areas.insert(area.start.as_u64(), area);
+        Ok(())
+    }
+}
+
+fn check_aligned(start: VirtAddr, size: u64) -> Result<(), VmaError> {
+    if size == 0 || !start.is_aligned(Size4KiB::SIZE) || !size.is_multiple_of(Size4KiB::SIZE) {
+        return Err(VmaError::Unaligned);
+    }
+    Ok(())
+}
+
+/// Returns the address `size` bytes after `start`, or `OutOfSpace` if that isn't a canonical address.
+fn checked_end(start: VirtAddr, size: u64) -> Result<VirtAddr, VmaError> {
+    start.as_u64().checked_add(size).and_then(|end| VirtAddr::try_new(end).ok()).ok_or(VmaError::OutOfSpace)
+}
+
+fn map_area(
+    area: &Vma,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), VmaError> {
+    let flags = area.page_flags();
+    let pages = Page::<Size4KiB>::range(Page::containing_address(area.start), Page::containing_address(area.end()));
+    match area.backing {
+        Backing::Reserved => {}
+        Backing::Anonymous => {
+            for page in pages {
+                let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe { phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, Size4KiB::SIZE as usize) };
+                match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                    Ok(flush) => flush.flush(),
+                    Err(error) => {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                        return Err(error.into());
+                    }
+                }
+            }
+        }
+        Backing::Mmio(phys, _) => unsafe {
+            let frame = PhysFrame::containing_address(phys);
+            map_contiguous(mapper, pages.start, frame, pages.count() as u64, flags, frame_allocator)?
+        },
+        Backing::Physical(phys) => unsafe {
+            map_physical_range(mapper, area.start, phys, area.size, flags, frame_allocator)?
+        },
+    }
+    Ok(())
+}
+
+/// Unmaps all pages of the area, whatever their size. Anonymous frames are deallocated. Reserved areas have
+/// nothing mapped, so whatever is found in their range belongs to someone else and is left alone.
+fn unmap_area(
+    area: &Vma,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<(), VmaError> {
+    if area.backing == Backing::Reserved {
+        return Ok(());
+    }
+    for_each_mapping(area, mapper, |mapper, addr, frame| {
+        match frame {
+            MappedFrame::Size4KiB(_) => {
+                let (frame, flush) = mapper.unmap(Page::<Size4KiB>::containing_address(addr))?;
+                flush.flush();
+                if area.backing == Backing::Anonymous {
+                    unsafe { frame_deallocator.deallocate_frame(frame) };
+                }
+            }
+            MappedFrame::Size2MiB(_) => mapper.unmap(Page::<Size2MiB>::containing_address(addr))?.1.flush(),
+            MappedFrame::Size1GiB(_) => mapper.unmap(Page::<Size1GiB>::containing_address(addr))?.1.flush(),
+        }
+        Ok::<(), VmaError>(())
+    })?;
+    address_space::kernel_mappings_changed();
+    Ok(())
+}
+
+/// Calls `f` with the start address and frame of every mapped page in the area.
+fn for_each_mapping<E>(
+    area: &Vma,
+    mapper: &mut OffsetPageTable<'static>,
+    mut f: impl FnMut(&mut OffsetPageTable<'static>, VirtAddr, MappedFrame) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut addr = area.start;
+    while addr < area.end() {
+        match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => {
+                let size = frame.size();
+                f(mapper, addr, frame)?;
+                addr += size;
+            }
+            _ => addr += Size4KiB::SIZE,
+        }
+    }
+    Ok(())
+}
+
+/// Records the areas the kernel set up before the manager was available. Requires the heap and the frame
+/// allocator to be initialized.
+pub fn init() {
+    let mut vmas = KERNEL_VMAS.lock();
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let heap = Vma {
+        name: "heap",
+        start: VirtAddr::new(HEAP_START as u64),
+        size: (HEAP_SIZE as u64).next_multiple_of(Size4KiB::SIZE),
+        flags: writable,
+        backing: Backing::Anonymous,
+        pinned: true,
+    };
+    vmas.record(heap).expect("heap overlaps another area");
+    vmas.record(Vma::reserved("stacks", VirtAddr::new(STACK_REGION_START), STACK_REGION_SIZE))
+        .expect("stack region overlaps another area");
+
+    // The bootloader maps all physical memory, this records the part covering usable RAM
+    let physical_memory_size = frame::with_frame_allocator(|allocator| allocator.frame_count()) as u64 * Size4KiB::SIZE;
+    let window = Vma {
+        name: "physical memory",
+        start: VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)),
+        size: physical_memory_size,
+        flags: writable,
+        backing: Backing::Physical(PhysAddr::new(0)),
+        pinned: true,
+    };
+    vmas.record(window).expect("physical memory window overlaps another area");
+}
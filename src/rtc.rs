@@ -0,0 +1,295 @@
+// CMOS Real Time Clock driver
+// The RTC keeps wall-clock time in the battery-backed CMOS and is accessed through an index port (0x70) and a
+// data port (0x71). Values may be stored in BCD or binary and the hour in 12 or 24 hour format, depending on
+// status register B. The RTC can also raise a periodic interrupt on IRQ 8 (secondary PIC).
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::IrqMutex;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::{interrupts::irq, time};
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_CENTURY: u8 = 0x32;       // Not guaranteed to exist - the ACPI FADT is the authoritative source
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+const NMI_DISABLE: u8 = 1 << 7;             // Set in the index port while accessing the CMOS
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+/// Access to the CMOS index/data port pair. The pair must be used atomically, so it lives behind a lock.
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    const fn new() -> Self {
+        Cmos {
+            index: Port::new(0x70),
+            data: Port::new(0x71),
+        }
+    }
+
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(NMI_DISABLE | register);
+            let value = self.data.read();
+            self.index.write(REG_STATUS_C);       // Re-enable NMIs, leaving a harmless register selected
+            value
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(NMI_DISABLE | register);
+            self.data.write(value);
+            self.index.write(REG_STATUS_C);
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+
+    /// Reads the raw time registers once the RTC is not in the middle of an update.
+    fn read_raw(&mut self) -> [u8; 7] {
+        while self.update_in_progress() {
+            core::hint::spin_loop();
+        }
+        [REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR, REG_CENTURY].map(|r| self.read(r))
+    }
+}
+
+static CMOS: IrqMutex<Cmos> = IrqMutex::new(Cmos::new());
+
+/// Unix timestamp read at `init`, paired with the monotonic clock to derive the current wall-clock time.
+static BOOT_UNIX_TIME: AtomicU64 = AtomicU64::new(0);
+static BOOT_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of periodic interrupts received since they were enabled.
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A calendar date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Converts the date to seconds since 1970-01-01 00:00:00 UTC.
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), u32::from(self.month), u32::from(self.day));
+        let seconds = u64::from(self.hour) * 3600 + u64::from(self.minute) * 60 + u64::from(self.second);
+        days as u64 * 86_400 + seconds
+    }
+
+    /// Converts seconds since the Unix epoch back to a calendar date.
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let (year, month, day) = civil_from_days((timestamp / 86_400) as i64);
+        let seconds = timestamp % 86_400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Reads the current date and time from the CMOS.
+pub fn read() -> DateTime {
+    let (raw, status_b) = {
+        let mut cmos = CMOS.lock();
+
+        // Read until two consecutive readings match, so we never return a value torn by an update
+        let mut raw = cmos.read_raw();
+        loop {
+            let again = cmos.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(REG_STATUS_B))
+    };
+
+    decode(raw, status_b)
+}
+
+/// Decodes raw CMOS time registers according to the format flags in status register B.
+fn decode(raw: [u8; 7], status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year, century] = raw;
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+    let pm = hour & HOUR_PM != 0;
+    let mut hour = convert(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 hour format: 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = match convert(century) {
+        c @ 19..=21 => u16::from(c),
+        _ => 20,        // Century register missing or garbage - assume the 21st century
+    };
+
+    DateTime {
+        year: century * 100 + u16::from(convert(year)),
+        month: convert(month),
+        day: convert(day),
+        hour,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+/// Number of days between 1970-01-01 and the given date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + i64::from(day_of_year);
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of `days_from_civil`. Returns (year, month, day).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Read the wall-clock time once and anchor it to the monotonic clock.
+/// Must be called after `time::init`.
+pub fn init() {
+    let boot_time = read();
+    BOOT_NANOS.store(time::now(), Ordering::Relaxed);
+    BOOT_UNIX_TIME.store(boot_time.to_unix_timestamp(), Ordering::Relaxed);
+    crate::serial_println!("RTC time: {}", boot_time);
+
+    irq::register_irq(irq::RTC, rtc_interrupt_handler).expect("registering RTC handler failed");
+}
+
+/// Returns the current Unix timestamp in seconds, derived from the RTC reading at boot and the monotonic clock.
+pub fn unix_time() -> u64 {
+    let elapsed = time::now() - BOOT_NANOS.load(Ordering::Relaxed);
+    BOOT_UNIX_TIME.load(Ordering::Relaxed) + elapsed / 1_000_000_000
+}
+
+/// Returns the current wall-clock time.
+pub fn now() -> DateTime {
+    DateTime::from_unix_timestamp(unix_time())
+}
+
+/// Enable the RTC periodic interrupt on IRQ 8. The resulting frequency is `32768 >> (rate - 1)` Hz,
+/// so valid rates range from 3 (8192 Hz) to 15 (2 Hz).
+pub fn enable_periodic_interrupt(rate: u8) {
+    assert!((3..=15).contains(&rate), "RTC rate must be between 3 and 15");
+
+    let mut cmos = CMOS.lock();
+    let status_a = cmos.read(REG_STATUS_A);
+    cmos.write(REG_STATUS_A, (status_a & 0xf0) | rate);
+    let status_b = cmos.read(REG_STATUS_B);
+    cmos.write(REG_STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+    cmos.read(REG_STATUS_C);                // Clear any pending interrupt so the next one can fire
+}
+
+/// Disable the RTC periodic interrupt.
+pub fn disable_periodic_interrupt() {
+    let mut cmos = CMOS.lock();
+    let status_b = cmos.read(REG_STATUS_B);
+    cmos.write(REG_STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+}
+
+/// Returns the number of periodic interrupts received so far.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// RTC interrupt handler. Status register C must be read, otherwise the RTC won't raise IRQ 8 again.
+fn rtc_interrupt_handler(_stack_frame: &InterruptStackFrame) {
+    CMOS.lock().read(REG_STATUS_C);
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_unix_timestamp_conversion() {
+    let date = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 42 };
+    assert_eq!(date.to_unix_timestamp(), 1_709_213_862);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_213_862), date);
+    assert_eq!(DateTime::from_unix_timestamp(0).to_unix_timestamp(), 0);
+}
+
+#[test_case]
+fn test_decode_bcd_12_hour() {
+    // 2025-12-31 11:59:58 PM in BCD, 12 hour format
+    let raw = [0x58, 0x59, HOUR_PM | 0x11, 0x31, 0x12, 0x25, 0x20];
+    let date = decode(raw, 0);
+    assert_eq!(date, DateTime { year: 2025, month: 12, day: 31, hour: 23, minute: 59, second: 58 });
+
+    // 12 AM is midnight
+    let raw = [0x00, 0x00, 0x12, 0x01, 0x01, 0x26, 0x20];
+    assert_eq!(decode(raw, 0).hour, 0);
+}
+
+#[test_case]
+fn test_decode_binary_24_hour() {
+    let raw = [5, 4, 17, 9, 8, 26, 20];
+    let date = decode(raw, STATUS_B_BINARY | STATUS_B_24_HOUR);
+    assert_eq!(date, DateTime { year: 2026, month: 8, day: 9, hour: 17, minute: 4, second: 5 });
+}
+
+#[test_case]
+fn test_periodic_interrupt() {
+    let start = periodic_ticks();
+    enable_periodic_interrupt(6);       // 1024 Hz
+
+    let deadline = time::now() + 100_000_000;
+    while periodic_ticks() == start && time::now() < deadline {
+        x86_64::instructions::hlt();
+    }
+    disable_periodic_interrupt();
+    assert!(periodic_ticks() > start);
+}
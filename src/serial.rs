@@ -1,23 +1,26 @@
 use uart_16550::SerialPort;
-use spin::Mutex;
+use crate::sync::IrqMutex;
 use lazy_static::lazy_static;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqMutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3f8) };    // Create serial port using first I/O port address
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqMutex::new(serial_port)
     };
 }
 
+/// Waits for a byte from the serial port and returns it.
+pub fn read_byte() -> u8 {
+    SERIAL1.lock().receive()
+}
+
 #[doc(hidden)]
+#[track_caller]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
 
-    // Temporarily disable interrupts while serial is locked
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial port failed.");
-    });
+    SERIAL1.lock().write_fmt(args).expect("Printing to serial port failed.");     // Interrupts stay disabled while serial is locked
 }
 
 // Print to the host through the serial interface
@@ -0,0 +1,226 @@
+// Interrupt-safe locking
+// A plain spin lock taken by both normal code and an interrupt handler deadlocks as soon as the interrupt arrives
+// while the lock is held. IrqMutex disables interrupts for as long as its guard lives and restores the previous
+// interrupt state when the guard is dropped, so callers no longer need to wrap their critical sections in
+// `without_interrupts`.
+// The guard also remembers where the lock was taken, which the watchdog reports for held locks. In debug builds
+// the owning CPU is tracked as well: taking a lock the current CPU already holds panics instead of spinning forever.
+
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicU32;
+
+/// Owner value of a lock that is not held.
+#[cfg(debug_assertions)]
+const NO_OWNER: u32 = u32::MAX;
+
+/// A spin lock that keeps interrupts disabled while it is held.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+    holder: AtomicPtr<Location<'static>>,
+    #[cfg(debug_assertions)]
+    owner_cpu: AtomicU32,
+}
+
+/// Guard of a locked IrqMutex. Unlocks and restores the interrupt state on drop.
+pub struct IrqMutexGuard<'a, T> {
+    lock: &'a IrqMutex<T>,
+    guard: Option<MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            inner: Mutex::new(value),
+            holder: AtomicPtr::new(ptr::null_mut()),
+            #[cfg(debug_assertions)]
+            owner_cpu: AtomicU32::new(NO_OWNER),
+        }
+    }
+
+    /// Disables interrupts and acquires the lock, spinning until it is available.
+    /// In debug builds this panics if the current CPU already holds the lock.
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let guard = loop {
+            if let Some(guard) = self.inner.try_lock() {
+                break guard;
+            }
+            #[cfg(debug_assertions)]
+            self.check_recursion();
+            core::hint::spin_loop();
+        };
+        self.acquired(guard, interrupts_were_enabled)
+    }
+
+    /// Acquires the lock if it is available, without spinning.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(self.acquired(guard, interrupts_were_enabled)),
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Returns true if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        !self.holder.load(Ordering::Relaxed).is_null()
+    }
+
+    /// Returns the source location at which the lock was taken, if it is held.
+    pub fn holder(&self) -> Option<&'static Location<'static>> {
+        unsafe { self.holder.load(Ordering::Relaxed).as_ref() }
+    }
+
+    /// Forcibly unlocks the lock.
+    ///
+    /// # Safety
+    /// The current holder must never touch the protected data again, e.g. because it has been interrupted by an
+    /// exception that does not return to it. The holder's saved interrupt state is not restored.
+    pub unsafe fn force_unlock(&self) {
+        self.release();
+        unsafe { self.inner.force_unlock() };
+    }
+
+    #[track_caller]
+    fn acquired<'a>(&'a self, guard: MutexGuard<'a, T>, interrupts_were_enabled: bool) -> IrqMutexGuard<'a, T> {
+        self.holder.store(Location::caller() as *const _ as *mut _, Ordering::Relaxed);
+        #[cfg(debug_assertions)]
+        self.owner_cpu.store(current_cpu(), Ordering::Relaxed);
+
+        IrqMutexGuard {
+            lock: self,
+            guard: Some(guard),
+            interrupts_were_enabled,
+        }
+    }
+
+    fn release(&self) {
+        #[cfg(debug_assertions)]
+        self.owner_cpu.store(NO_OWNER, Ordering::Relaxed);
+        self.holder.store(ptr::null_mut(), Ordering::Relaxed);
+    }
+
+    /// Panics if the lock is held by the current CPU. Interrupts are disabled while a lock is held, so nothing
+    /// else on this CPU could ever release it.
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn check_recursion(&self) {
+        if self.owner_cpu.load(Ordering::Relaxed) != current_cpu() {
+            return;
+        }
+
+        let holder = self.holder();
+        // The holder is never resumed once we panic - release the lock so the panic handler can't deadlock on it
+        unsafe { self.force_unlock() };
+        match holder {
+            Some(location) => panic!("recursive IrqMutex lock, already acquired at {}", location),
+            None => panic!("recursive IrqMutex lock"),
+        }
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+        drop(self.guard.take());        // Unlock before interrupts are enabled again
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// APIC ID of the CPU the kernel runs on, read on first use. `cpuid` is too slow to run on every lock acquisition,
+/// and the kernel only ever runs on the boot CPU.
+#[cfg(debug_assertions)]
+static CURRENT_CPU: AtomicU32 = AtomicU32::new(NO_OWNER);
+
+/// Returns the initial APIC ID of the current CPU.
+#[cfg(debug_assertions)]
+fn current_cpu() -> u32 {
+    let cpu = CURRENT_CPU.load(Ordering::Relaxed);
+    if cpu != NO_OWNER {
+        return cpu;
+    }
+    let cpu = core::arch::x86_64::__cpuid(1).ebx >> 24;
+    CURRENT_CPU.store(cpu, Ordering::Relaxed);
+    cpu
+}
+
+#[test_case]
+fn test_lock_restores_interrupt_state() {
+    let mutex = IrqMutex::new(0);
+
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = mutex.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(mutex.is_locked());
+    }
+    assert!(interrupts::are_enabled());
+    assert!(!mutex.is_locked());
+
+    // Nested locks only restore interrupts once the outer guard is dropped
+    let other = IrqMutex::new(0);
+    {
+        let _outer = mutex.lock();
+        {
+            let _inner = other.lock();
+        }
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_try_lock_fails_while_held() {
+    let mutex = IrqMutex::new(());
+    let guard = mutex.lock();
+    assert!(mutex.try_lock().is_none());
+    assert!(mutex.holder().is_some());
+    drop(guard);
+    assert!(mutex.try_lock().is_some());
+}
+
+#[test_case]
+fn test_holder_is_the_caller() {
+    let locked = crate::allocator::Locked::new(0);
+    let guard = locked.lock();
+    let line = line!() - 1;
+    let holder = locked.holder();
+    drop(guard);
+    assert_eq!(holder.map(|location| (location.file(), location.line())), Some((file!(), line)));
+}
@@ -0,0 +1,221 @@
+// System calls
+// Less privileged code requests kernel services with the `syscall` instruction. The system call number is passed
+// in rax and up to six arguments in rdi, rsi, rdx, r10, r8 and r9 (rcx and r11 are taken by the instruction).
+// The result comes back in rax: the return value on success, or the negated error code. Values from -4095 to -1
+// are reserved for errors, like on Linux.
+// `entry` contains the assembly entry path. The dispatcher below is independent of it, so system calls can be
+// exercised from ring 0 by calling `dispatch` directly.
+
+use crate::{print, serial_print, memory, rtc, time};
+use crate::memory::address_space::USER_P4_ENTRIES;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+pub mod entry;
+
+// System call numbers
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_UPTIME: u64 = 1;
+pub const SYS_UNIX_TIME: u64 = 2;
+const SYSCALL_COUNT: usize = 3;
+
+// File descriptors accepted by SYS_WRITE
+pub const FD_CONSOLE: u64 = 1;
+pub const FD_SERIAL: u64 = 2;
+
+/// Upper bound on the size of a buffer passed to a system call.
+const MAX_BUFFER_LEN: usize = 1 << 20;
+
+/// Largest error code. Results in the range -MAX_ERROR_CODE..=-1 are errors.
+const MAX_ERROR_CODE: u64 = 4095;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    /// There is no system call with this number
+    InvalidSyscall = 1,
+    /// An argument is out of range
+    InvalidArgument = 2,
+    /// A buffer argument refers to memory that can't be accessed
+    BadAddress = 3,
+    /// A string argument is not valid UTF-8
+    InvalidUtf8 = 4,
+}
+
+impl SyscallError {
+    fn from_code(code: u64) -> Option<SyscallError> {
+        match code {
+            1 => Some(SyscallError::InvalidSyscall),
+            2 => Some(SyscallError::InvalidArgument),
+            3 => Some(SyscallError::BadAddress),
+            4 => Some(SyscallError::InvalidUtf8),
+            _ => None,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+/// Signature of a system call implementation.
+type SyscallHandler = fn(&SyscallArgs) -> SyscallResult;
+
+/// System call implementations, indexed by system call number.
+static SYSCALL_TABLE: [SyscallHandler; SYSCALL_COUNT] = {
+    let mut table: [SyscallHandler; SYSCALL_COUNT] = [sys_invalid; SYSCALL_COUNT];
+    table[SYS_WRITE as usize] = sys_write;
+    table[SYS_UPTIME as usize] = sys_uptime;
+    table[SYS_UNIX_TIME as usize] = sys_unix_time;
+    table
+};
+
+/// Raw system call arguments in register order: rdi, rsi, rdx, r10, r8, r9.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallArgs(pub [u64; 6]);
+
+impl SyscallArgs {
+    /// Returns the argument with the given index as an integer.
+    pub fn get(&self, index: usize) -> u64 {
+        self.0[index]
+    }
+
+    /// Returns the argument with the given index as a size.
+    pub fn usize(&self, index: usize) -> Result<usize, SyscallError> {
+        usize::try_from(self.get(index)).map_err(|_| SyscallError::InvalidArgument)
+    }
+
+    /// Returns the buffer described by a pointer and a length argument.
+    /// Fails if the buffer is too large, wraps around, or isn't completely in mapped, user accessible pages of the
+    /// user half - kernel memory must never be readable through a system call.
+    pub fn bytes(&self, pointer_index: usize, len_index: usize) -> Result<&[u8], SyscallError> {
+        let len = self.usize(len_index)?;
+        if len > MAX_BUFFER_LEN {
+            return Err(SyscallError::InvalidArgument);
+        }
+        if len == 0 {
+            return Ok(&[]);
+        }
+
+        let start = VirtAddr::try_new(self.get(pointer_index)).map_err(|_| SyscallError::BadAddress)?;
+        let last = start.as_u64().checked_add(len as u64 - 1).ok_or(SyscallError::BadAddress)?;
+        let last = VirtAddr::try_new(last).map_err(|_| SyscallError::BadAddress)?;
+        let in_user_half = |addr: VirtAddr| USER_P4_ENTRIES.contains(&usize::from(addr.p4_index()));
+        if !in_user_half(start) || !in_user_half(last) {
+            return Err(SyscallError::BadAddress);
+        }
+
+        // Check one address per page, plus the last byte
+        let mut page = start.align_down(4096u64);
+        while page <= last {
+            if !is_user_accessible(page.max(start)) {
+                return Err(SyscallError::BadAddress);
+            }
+            page += 4096u64;
+        }
+        Ok(unsafe { core::slice::from_raw_parts(start.as_ptr(), len) })
+    }
+
+    /// Returns the UTF-8 string described by a pointer and a length argument.
+    pub fn str(&self, pointer_index: usize, len_index: usize) -> Result<&str, SyscallError> {
+        core::str::from_utf8(self.bytes(pointer_index, len_index)?).map_err(|_| SyscallError::InvalidUtf8)
+    }
+}
+
+/// Returns true if the address is mapped with all levels of the page tables allowing user access.
+fn is_user_accessible(addr: VirtAddr) -> bool {
+    let mut user = true;
+    let mut mapped = false;
+    let walked = memory::walk_page_tables(addr, |entry| {
+        user &= entry.flags.contains(PageTableFlags::USER_ACCESSIBLE);
+        mapped = entry.flags.contains(PageTableFlags::PRESENT)
+            && (entry.level == 1 || entry.flags.contains(PageTableFlags::HUGE_PAGE));
+    });
+    walked && mapped && user
+}
+
+/// Executes the system call with the given number.
+pub fn dispatch(number: u64, args: &SyscallArgs) -> SyscallResult {
+    let handler = usize::try_from(number)
+        .ok()
+        .and_then(|number| SYSCALL_TABLE.get(number))
+        .ok_or(SyscallError::InvalidSyscall)?;
+    handler(args)
+}
+
+/// Encodes a system call result for the rax register.
+pub fn encode_result(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => {
+            debug_assert!(value < MAX_ERROR_CODE.wrapping_neg(), "system call value collides with the error range");
+            value
+        }
+        Err(error) => (error as u64).wrapping_neg(),
+    }
+}
+
+/// Decodes a system call result returned in the rax register.
+pub fn decode_result(raw: u64) -> SyscallResult {
+    let code = raw.wrapping_neg();
+    if (1..=MAX_ERROR_CODE).contains(&code) {
+        return Err(SyscallError::from_code(code).unwrap_or(SyscallError::InvalidSyscall));
+    }
+    Ok(raw)
+}
+
+fn sys_invalid(_args: &SyscallArgs) -> SyscallResult {
+    Err(SyscallError::InvalidSyscall)
+}
+
+/// write(fd, buffer, len) - writes a UTF-8 string to the console or the serial port, returns the bytes written.
+fn sys_write(args: &SyscallArgs) -> SyscallResult {
+    let text = args.str(1, 2)?;
+    match args.get(0) {
+        FD_CONSOLE => { print!("{}", text); }
+        FD_SERIAL => { serial_print!("{}", text); }
+        _ => return Err(SyscallError::InvalidArgument),
+    }
+    Ok(text.len() as u64)
+}
+
+/// uptime() - returns the nanoseconds since boot.
+fn sys_uptime(_args: &SyscallArgs) -> SyscallResult {
+    Ok(time::now())
+}
+
+/// unix_time() - returns the seconds since the Unix epoch.
+fn sys_unix_time(_args: &SyscallArgs) -> SyscallResult {
+    Ok(rtc::unix_time())
+}
+
+#[test_case]
+fn test_dispatch() {
+    let args = SyscallArgs([FD_SERIAL, 0, 0, 0, 0, 0]);
+    assert_eq!(dispatch(SYS_WRITE, &args), Ok(0));
+    assert!(dispatch(SYS_UPTIME, &SyscallArgs([0; 6])).unwrap() > 0);
+    assert_eq!(dispatch(SYSCALL_COUNT as u64, &SyscallArgs([0; 6])), Err(SyscallError::InvalidSyscall));
+}
+
+#[test_case]
+fn test_argument_validation() {
+    // Not mapped: the null page
+    let args = SyscallArgs([FD_SERIAL, 0, 16, 0, 0, 0]);
+    assert_eq!(dispatch(SYS_WRITE, &args), Err(SyscallError::BadAddress));
+
+    // Wraps around the end of the address space
+    let args = SyscallArgs([FD_SERIAL, u64::MAX - 4, 16, 0, 0, 0]);
+    assert_eq!(dispatch(SYS_WRITE, &args), Err(SyscallError::BadAddress));
+
+    // Kernel memory, mapped but outside the user half
+    let message = "kernel data";
+    let args = SyscallArgs([FD_SERIAL, message.as_ptr() as u64, message.len() as u64, 0, 0, 0]);
+    assert_eq!(dispatch(SYS_WRITE, &args), Err(SyscallError::BadAddress));
+
+    let args = SyscallArgs([7, 0, 0, 0, 0, 0]);
+    assert_eq!(dispatch(SYS_WRITE, &args), Err(SyscallError::InvalidArgument));
+}
+
+#[test_case]
+fn test_result_encoding() {
+    assert_eq!(decode_result(encode_result(Ok(42))), Ok(42));
+    assert_eq!(encode_result(Err(SyscallError::BadAddress)), -3i64 as u64);
+    assert_eq!(decode_result(encode_result(Err(SyscallError::BadAddress))), Err(SyscallError::BadAddress));
+}
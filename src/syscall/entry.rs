@@ -0,0 +1,112 @@
+// SYSCALL entry path
+// The `syscall` instruction jumps to the address in the LSTAR MSR with the kernel segments from STAR, but keeps
+// the caller's stack. The entry stub uses `swapgs` to reach the per-CPU data, saves the caller's stack pointer
+// there and switches to the kernel's system call stack before anything is pushed. It then saves the caller's
+// state as a `SyscallFrame`, enables interrupts and calls the dispatcher. `sysretq` returns to ring 3 with the
+// saved instruction pointer (rcx) and flags (r11).
+
+use crate::gdt;
+use crate::memory::stack::{self, StackError};
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::SyscallArgs;
+use x86_64::registers::model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Size of the guard-paged stack system calls run on, in pages.
+const STACK_PAGES: u64 = 5;
+
+/// Per-CPU data reached through the GS segment while in the kernel.
+#[repr(C)]
+struct CpuLocal {
+    /// Stack pointer loaded on system call entry
+    kernel_stack_top: AtomicU64,
+    /// Stack pointer of the caller, saved on system call entry
+    user_stack_pointer: AtomicU64,
+}
+
+static CPU_LOCAL: CpuLocal = CpuLocal {
+    kernel_stack_top: AtomicU64::new(0),
+    user_stack_pointer: AtomicU64::new(0),
+};
+
+/// Caller state saved by the entry stub, in reverse push order.
+#[repr(C)]
+struct SyscallFrame {
+    rax: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    r10: u64,
+    r8: u64,
+    r9: u64,
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+}
+
+/// Enable the `syscall` instruction and point it at the entry stub. Requires the GDT to be loaded.
+/// System calls can't be handled before `init_stack` has allocated their stack.
+pub fn init() {
+    // The kernel runs with GS base 0 - swapgs on entry exchanges it with the per-CPU data pointer
+    KernelGsBase::write(VirtAddr::from_ptr(&CPU_LOCAL));
+
+    let selectors = gdt::selectors();
+    Star::write(selectors.user_code_selector, selectors.user_data_selector, selectors.code_selector, selectors.data_selector)
+        .expect("GDT segment order doesn't match SYSCALL/SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as extern "C" fn() as usize as u64));
+    // Flags cleared on entry: the stub must not be interrupted before it has switched stacks
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG | RFlags::ALIGNMENT_CHECK);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Allocates the guard-paged stack system calls run on.
+pub fn init_stack(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), StackError> {
+    let stack = stack::allocate_stack("syscall", STACK_PAGES, mapper, frame_allocator)?;
+    CPU_LOCAL.kernel_stack_top.store(stack.end().as_u64(), Ordering::Relaxed);
+    Ok(())
+}
+
+#[unsafe(naked)]
+extern "C" fn syscall_entry() {
+    core::arch::naked_asm!(
+        "swapgs",
+        "mov gs:[{user_stack}], rsp",
+        "mov rsp, gs:[{kernel_stack}]",
+        "push qword ptr gs:[{user_stack}]",
+        "push rcx",
+        "push r11",
+        "push r9", "push r8", "push r10", "push rdx", "push rsi", "push rdi", "push rax",
+        // 10 words on a 16 byte aligned stack keep it aligned for the call
+        "sti",
+        "mov rdi, rsp",
+        "call {handler}",
+        "cli",
+        "add rsp, 8",                       // The result replaces the saved rax
+        "pop rdi", "pop rsi", "pop rdx", "pop r10", "pop r8", "pop r9",
+        "pop r11",
+        "pop rcx",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+        user_stack = const offset_of!(CpuLocal, user_stack_pointer),
+        kernel_stack = const offset_of!(CpuLocal, kernel_stack_top),
+        handler = sym syscall_handler,
+    );
+}
+
+extern "C" fn syscall_handler(frame: &SyscallFrame) -> u64 {
+    let args = SyscallArgs([frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9]);
+    super::encode_result(super::dispatch(frame.rax, &args))
+}
+
+#[test_case]
+fn test_syscall_enabled() {
+    assert!(Efer::read().contains(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    assert_eq!(LStar::read().as_u64(), syscall_entry as extern "C" fn() as usize as u64);
+}
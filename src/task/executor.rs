@@ -1,8 +1,49 @@
 use super::{Task, TaskId};
+use crate::{interrupts::deferred, sync::IrqMutex, watchdog};
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
 
+/// Scheduling state of a spawned task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Woken and waiting in the task queue
+    Ready,
+    /// Being polled right now
+    Running,
+    /// Waiting to be woken
+    Waiting,
+}
+
+/// Diagnostic information about a spawned task.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub state: TaskState,
+    pub polls: u64,
+}
+
+/// Information about all tasks that have been spawned and not yet completed, for diagnostics such as the debugger.
+static TASK_INFO: IrqMutex<BTreeMap<TaskId, TaskInfo>> = IrqMutex::new(BTreeMap::new());
+
+/// Calls `f` for every task that has been spawned and not yet completed. Never blocks: returns false without
+/// calling `f` if the task list is locked, e.g. because the code that was interrupted is updating it.
+pub fn for_each_task(mut f: impl FnMut(&TaskInfo)) -> bool {
+    match TASK_INFO.try_lock() {
+        Some(tasks) => {
+            tasks.values().for_each(&mut f);
+            true
+        }
+        None => false,
+    }
+}
+
+fn set_task_state(task_id: TaskId, state: TaskState) {
+    if let Some(info) = TASK_INFO.lock().get_mut(&task_id) {
+        info.state = state;
+    }
+}
+
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
@@ -24,12 +65,14 @@ impl Executor {
             panic!("Task with same ID already in tasks");
         }
         self.task_queue.push(task_id).expect("queue full");
+        TASK_INFO.lock().insert(task_id, TaskInfo { id: task_id.0, state: TaskState::Ready, polls: 0 });
     }
 
     fn run_ready_tasks(&mut self) {
         // Destructure self to avoid borrow checker errors
         let Self { tasks, task_queue, waker_cache } = self;
         while let Some(task_id) = task_queue.pop() {
+            watchdog::heartbeat();
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue,
@@ -41,12 +84,27 @@ impl Executor {
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            if let Some(info) = TASK_INFO.lock().get_mut(&task_id) {
+                info.state = TaskState::Running;
+                info.polls += 1;
+            }
+            watchdog::set_current_task(Some(task_id.0));
+            let result = task.poll(&mut context);
+            watchdog::set_current_task(None);
+            match result {
                 Poll::Ready(()) => {
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    TASK_INFO.lock().remove(&task_id);
+                }
+                Poll::Pending => {
+                    // The task may have woken itself while it was polled
+                    if let Some(info) = TASK_INFO.lock().get_mut(&task_id)
+                        && info.state == TaskState::Running
+                    {
+                        info.state = TaskState::Waiting;
+                    }
                 }
-                Poll::Pending => {}
             }
         }
     }
@@ -55,7 +113,7 @@ impl Executor {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();                  // Disable interrupts to avoid race conditions between is_empty() and hlt()
-        if self.task_queue.is_empty() {
+        if self.task_queue.is_empty() && !deferred::has_pending() {
             enable_and_hlt();
         }
         else {
@@ -65,6 +123,8 @@ impl Executor {
 
     pub fn run(&mut self) -> ! {
         loop {
+            watchdog::heartbeat();
+            deferred::run_pending();            // Deferred interrupt work takes priority over tasks
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
@@ -87,6 +147,7 @@ impl TaskWaker {
 
     fn wake_task(&self) {
         self.task_queue.push(self.task_id).expect("task_queue full");
+        set_task_state(self.task_id, TaskState::Ready);
     }
 }
 
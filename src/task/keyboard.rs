@@ -8,17 +8,33 @@ use futures_util::{
     stream::{Stream, StreamExt},
     task::AtomicWaker,
 };
+use x86_64::structures::idt::InterruptStackFrame;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use crate::{print, println, task::keyboard};
+use crate::{print, println, interrupts::{deferred, irq, stats, PIC_1_OFFSET}};
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
 
+/// Register the keyboard interrupt handler with the IRQ dispatcher.
+pub fn init() {
+    irq::register_irq(irq::KEYBOARD, keyboard_interrupt_handler).expect("registering keyboard handler failed");
+}
+
+/// Keyboard interrupt handler function
+fn keyboard_interrupt_handler(_stack_frame: &InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);     // PS/2 controller - I/O port 0x60
+    let scancode: u8 = unsafe { port.read() };
+    add_scancode(scancode);
+}
+
 /// Called by the keyboard interrupt handler - must not block or allocate.
-pub(crate) fn add_scancode(scancode: u8) {
+fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            println!("WARNING: Scancode queue full; dropping keyboard input.");
+            stats::record_dropped(PIC_1_OFFSET + irq::KEYBOARD);
+            let _ = deferred::schedule(warn_scancode_dropped, 0);   // Don't print from the interrupt handler
         }
         else {
             WAKER.wake();   // Notify the executor of the successful add
@@ -29,6 +45,10 @@ pub(crate) fn add_scancode(scancode: u8) {
     }
 }
 
+fn warn_scancode_dropped(_: usize) {
+    println!("WARNING: Scancode queue full; dropping keyboard input.");
+}
+
 pub struct ScancodeStream {
     _private: (),               // Prevents construction from outside the module, forcing use of new()
 }
@@ -0,0 +1,45 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+pub mod pit;
+pub mod tsc;
+
+/// TSC value at the time of calibration. Timestamps are measured relative to it.
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Calibrate the TSC and start the monotonic clock.
+pub fn init() {
+    let hz = tsc::calibrate();
+    BOOT_TSC.store(tsc::read(), Ordering::Relaxed);
+
+    if !tsc::is_invariant() {
+        crate::serial_println!("WARNING: TSC is not invariant; timestamps may drift.");
+    }
+    crate::serial_println!("TSC frequency: {} kHz", hz / 1000);
+}
+
+/// Returns a monotonic timestamp in nanoseconds since `init`, or 0 if the clock has not been initialized.
+pub fn now() -> u64 {
+    let boot = BOOT_TSC.load(Ordering::Relaxed);
+    if boot == 0 {
+        return 0;
+    }
+    tsc::cycles_to_ns(tsc::read().saturating_sub(boot))
+}
+
+/// Returns the time elapsed since `init`.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(now())
+}
+
+#[test_case]
+fn test_tsc_calibrated() {
+    assert!(tsc::frequency().is_some());
+}
+
+#[test_case]
+fn test_now_is_monotonic() {
+    let first = now();
+    let second = now();
+    assert!(second >= first);
+}
@@ -0,0 +1,56 @@
+// Programmable Interval Timer (Intel 8253/8254) helpers.
+// Channel 0 drives the timer interrupt and is left untouched here. Channel 2 is normally wired to the PC speaker,
+// but its gate and output can be controlled and read through port 0x61, which makes it a convenient
+// known-frequency reference for calibrating other clocks.
+
+use x86_64::instructions::port::Port;
+
+/// Input frequency of the PIT oscillator in Hz.
+pub const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+
+const CHANNEL_2_DATA_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+const SPEAKER_CONTROL_PORT: u16 = 0x61;
+
+const SPEAKER_GATE_BIT: u8 = 1 << 0;      // Gate input of channel 2
+const SPEAKER_ENABLE_BIT: u8 = 1 << 1;    // Connects channel 2 output to the speaker
+const CHANNEL_2_OUT_BIT: u8 = 1 << 5;     // Current output level of channel 2
+
+/// Busy-waits for the given number of PIT ticks using channel 2 in one-shot mode.
+/// The closure is called right after the countdown is started and its result is returned once the countdown expires,
+/// together with the value returned by `after`. This lets callers sample another clock at both ends of the window.
+///
+/// Interrupts should be disabled by the caller to keep the measured window tight.
+pub fn one_shot<T, U>(ticks: u16, before: impl FnOnce() -> T, after: impl FnOnce() -> U) -> (T, U) {
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut data: Port<u8> = Port::new(CHANNEL_2_DATA_PORT);
+    let mut control: Port<u8> = Port::new(SPEAKER_CONTROL_PORT);
+
+    unsafe {
+        // Disable the speaker and drop the gate so the counter does not run while it is programmed
+        let value = control.read() & !(SPEAKER_ENABLE_BIT | SPEAKER_GATE_BIT);
+        control.write(value);
+
+        // Channel 2, access mode lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+        command.write(0b1011_0000);
+        data.write((ticks & 0xff) as u8);
+        data.write((ticks >> 8) as u8);
+
+        // Raising the gate starts the countdown
+        control.write(value | SPEAKER_GATE_BIT);
+    }
+    let start = before();
+
+    // OUT2 goes high once the counter reaches zero
+    while unsafe { control.read() } & CHANNEL_2_OUT_BIT == 0 {
+        core::hint::spin_loop();
+    }
+    let end = after();
+
+    unsafe {
+        let value = control.read() & !SPEAKER_GATE_BIT;
+        control.write(value);
+    }
+
+    (start, end)
+}
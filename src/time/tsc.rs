@@ -0,0 +1,89 @@
+// Time Stamp Counter support.
+// The TSC is a 64-bit counter incremented by the CPU, read with the `rdtsc` instruction. On CPUs with an invariant
+// TSC it ticks at a constant rate regardless of power states, which makes it a cheap high-resolution clock once
+// its frequency is known. The frequency is taken from CPUID leaf 0x15 when the CPU reports it, and is otherwise
+// measured against the PIT.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::pit;
+
+/// Length of a single PIT calibration window (~10 ms).
+const CALIBRATION_TICKS: u16 = (pit::PIT_FREQUENCY_HZ / 100) as u16;
+
+/// Number of calibration windows. The shortest one is used since it was disturbed the least.
+const CALIBRATION_ROUNDS: usize = 5;
+
+static FREQUENCY_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Nanoseconds per TSC cycle as a 32.32 fixed point number, so conversions don't need a division.
+static NS_PER_CYCLE: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the current value of the time stamp counter.
+#[inline]
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Returns true if the CPU reports an invariant TSC (CPUID 0x8000_0007, EDX bit 8).
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Returns the calibrated TSC frequency in Hz, or `None` if `calibrate` has not run yet.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Converts a number of TSC cycles to nanoseconds. Returns 0 before calibration.
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    let ns_per_cycle = NS_PER_CYCLE.load(Ordering::Relaxed);
+    ((u128::from(cycles) * u128::from(ns_per_cycle)) >> 32) as u64
+}
+
+/// Determines the TSC frequency and stores it for later conversions. Returns the frequency in Hz.
+pub fn calibrate() -> u64 {
+    let hz = frequency_from_cpuid().unwrap_or_else(frequency_from_pit);
+
+    FREQUENCY_HZ.store(hz, Ordering::Relaxed);
+    NS_PER_CYCLE.store(((1_000_000_000u128 << 32) / u128::from(hz)) as u64, Ordering::Relaxed);
+    hz
+}
+
+/// Reads the TSC frequency from CPUID leaf 0x15 (TSC/crystal clock ratio), if the CPU enumerates it.
+fn frequency_from_cpuid() -> Option<u64> {
+    if __cpuid(0).eax < 0x15 {
+        return None;
+    }
+
+    let leaf = __cpuid(0x15);
+    let (denominator, numerator, crystal_hz) = (leaf.eax, leaf.ebx, leaf.ecx);
+    if denominator == 0 || numerator == 0 || crystal_hz == 0 {
+        return None;    // Ratio or crystal frequency not enumerated
+    }
+    Some(u64::from(crystal_hz) * u64::from(numerator) / u64::from(denominator))
+}
+
+/// Measures the TSC frequency by counting cycles over a fixed number of PIT ticks.
+fn frequency_from_pit() -> u64 {
+    use x86_64::instructions::interrupts;
+
+    let cycles = interrupts::without_interrupts(|| {
+        (0..CALIBRATION_ROUNDS)
+            .map(|_| {
+                let (start, end) = pit::one_shot(CALIBRATION_TICKS, read, read);
+                end - start
+            })
+            .min()
+            .unwrap()
+    });
+
+    cycles * pit::PIT_FREQUENCY_HZ / u64::from(CALIBRATION_TICKS)
+}
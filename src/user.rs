@@ -0,0 +1,47 @@
+// User mode
+// Code in ring 3 can't touch kernel pages, execute privileged instructions or change the interrupt flag. It
+// re-enters the kernel through interrupts and exceptions, which switch to the privilege stack (RSP0) from the TSS,
+// and through the `syscall` instruction, which the system call entry path handles.
+// The kernel runs with a GS base of 0 and the per-CPU data in KernelGsBase, which is also the state user mode
+// expects - so entering user mode doesn't need a `swapgs`.
+
+use crate::gdt;
+use core::arch::asm;
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+/// RFLAGS bit 1 is reserved and always set.
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+/// Starts executing `entry` in ring 3 with the stack pointer `stack` and interrupts enabled. Never returns - the
+/// kernel only regains control through interrupts, exceptions and system calls.
+///
+/// # Safety
+/// `entry` and the stack must be mapped user accessible in the active page table, and the privilege stack in the
+/// TSS must be set up to handle interrupts arriving from user mode.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    let code_selector = u64::from(selectors.user_code_selector.0);
+    let data_selector = u64::from(selectors.user_data_selector.0);
+    let rflags = RFlags::INTERRUPT_FLAG.bits() | RFLAGS_RESERVED;
+
+    unsafe {
+        asm!(
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            // Build the interrupt return frame: SS, RSP, RFLAGS, CS, RIP
+            "push {data}",
+            "push {stack}",
+            "push {rflags}",
+            "push {code}",
+            "push {entry}",
+            "iretq",
+            data = in(reg) data_selector,
+            stack = in(reg) stack.as_u64(),
+            rflags = in(reg) rflags,
+            code = in(reg) code_selector,
+            entry = in(reg) entry.as_u64(),
+            options(noreturn)
+        );
+    }
+}
@@ -1,10 +1,10 @@
 use volatile::Volatile;         // Import the Volatile type to prevent compiler optimizations for VGA buffer writes
 use core::fmt;                  // Support Rust's formatting macros to easily print different types
 use lazy_static::lazy_static;   // For initializing static Writer at runtime
-use spin::Mutex;                // Add safe interior mutability for static Writer
+use crate::sync::IrqMutex;       // Add safe interior mutability for static Writer
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(
+    pub static ref WRITER: IrqMutex<Writer> = IrqMutex::new(
         Writer {
             column_position: 0,
             color_code: ColorCode::new(Color::Yellow, Color::Black),
@@ -130,13 +130,11 @@ impl fmt::Write for Writer {
 
 // Function needs to be public so it can be accessed from the print! macro, but is hidden from documentation.
 #[doc(hidden)]
+#[track_caller]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
 
-    // Temporarily disable interrupts while writer is locked
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();     // Interrupts stay disabled while the writer is locked
 }
 
 // Macro for print functionality (modified from standard library macro)
@@ -0,0 +1,180 @@
+// NMI watchdog
+// The executor loop regularly reports a heartbeat. A periodic check compares the time of the last heartbeat
+// against a timeout and, if the kernel has stalled, dumps the interrupted instruction pointer, the running task
+// and the held kernel locks to serial before panicking.
+// The check runs from an NMI raised by performance counter 0 overflowing, so it also fires while interrupts are
+// disabled (e.g. a spin lock deadlock). CPUs without an architectural PMU (such as QEMU without KVM) fall back
+// to checking from the timer interrupt, which still catches tasks that spin with interrupts enabled.
+
+use crate::interrupts::{irq, lapic};
+use crate::{serial_println, time};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+// Event select: unhalted core cycles, counted in user and kernel mode, interrupt on overflow
+const EVENT_UNHALTED_CORE_CYCLES: u64 = 0x3C;
+const PERFEVTSEL_USR: u64 = 1 << 16;
+const PERFEVTSEL_OS: u64 = 1 << 17;
+const PERFEVTSEL_INT: u64 = 1 << 20;
+const PERFEVTSEL_EN: u64 = 1 << 22;
+
+/// Writes to the performance counter are sign extended from 32 bits, which limits the period.
+const MAX_PERIOD: u64 = (1 << 31) - 1;
+
+/// Task ID value meaning that no task is being polled.
+const NO_TASK: u64 = u64::MAX;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static FIRED: AtomicBool = AtomicBool::new(false);
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+static TIMEOUT_NS: AtomicU64 = AtomicU64::new(0);
+static LAST_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(NO_TASK);
+
+/// Performance counter reload value, or 0 if the PMU is not used.
+static PMC_PERIOD: AtomicU64 = AtomicU64::new(0);
+
+/// Default time without a heartbeat after which the watchdog fires.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Arm the watchdog. Requires the clock, the interrupt controllers and the VMA manager to be initialized.
+pub fn init(timeout: Duration) {
+    heartbeat();
+    TIMEOUT_NS.store(timeout.as_nanos() as u64, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+
+    match pmu_counters() {
+        Some(version) => {
+            // Fire about once a second (the TSC rate approximates the core clock)
+            let period = time::tsc::frequency().unwrap_or(MAX_PERIOD).min(MAX_PERIOD);
+            PMC_PERIOD.store(period, Ordering::Relaxed);
+            lapic::init();
+            lapic::enable();
+            unsafe {
+                Msr::new(IA32_PERFEVTSEL0).write(0);
+                if version >= 2 {
+                    let mut global_ctrl = Msr::new(IA32_PERF_GLOBAL_CTRL);
+                    let value = global_ctrl.read();
+                    global_ctrl.write(value | 1);
+                }
+                arm_counter(period);
+                Msr::new(IA32_PERFEVTSEL0).write(
+                    EVENT_UNHALTED_CORE_CYCLES | PERFEVTSEL_USR | PERFEVTSEL_OS | PERFEVTSEL_INT | PERFEVTSEL_EN
+                );
+            }
+            serial_println!("Watchdog armed using performance counter NMIs");
+        }
+        None => {
+            irq::register_irq(irq::TIMER, timer_check).expect("registering watchdog timer handler failed");
+            serial_println!("WARNING: no performance counters; watchdog checks from the timer interrupt");
+        }
+    }
+}
+
+/// Reports that the kernel is making progress. Called from the executor loop.
+pub fn heartbeat() {
+    LAST_HEARTBEAT.store(time::now(), Ordering::Relaxed);
+}
+
+/// Stops the watchdog from firing, e.g. while the debugger waits for input with the kernel stopped.
+pub fn suspend() {
+    SUSPENDED.store(true, Ordering::Relaxed);
+}
+
+/// Lets the watchdog fire again after `suspend`. The time spent suspended doesn't count as a stall.
+pub fn resume() {
+    heartbeat();
+    SUSPENDED.store(false, Ordering::Relaxed);
+}
+
+/// Records the ID of the task that is currently polled, or `None` while the executor itself runs.
+pub fn set_current_task(task_id: Option<u64>) {
+    CURRENT_TASK.store(task_id.unwrap_or(NO_TASK), Ordering::Relaxed);
+}
+
+/// Returns the architectural performance monitoring version if general purpose counter 0 is available.
+fn pmu_counters() -> Option<u8> {
+    use core::arch::x86_64::__cpuid;
+
+    if __cpuid(0).eax < 0xA {
+        return None;
+    }
+    let eax = __cpuid(0xA).eax;
+    let version = (eax & 0xff) as u8;
+    let counters = (eax >> 8) & 0xff;
+    (version > 0 && counters > 0).then_some(version)
+}
+
+/// Loads the counter so it overflows after `period` cycles and routes the overflow to an NMI.
+unsafe fn arm_counter(period: u64) {
+    unsafe {
+        Msr::new(IA32_PMC0).write(period.wrapping_neg() & 0xffff_ffff);
+        Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);         // Clear the overflow status of counter 0
+        // The LVT entry is masked automatically on each overflow interrupt, so it needs to be rewritten
+        lapic::write(lapic::REG_LVT_PERFORMANCE_COUNTER, lapic::LVT_DELIVERY_NMI);
+    }
+}
+
+/// Called by the NMI handler.
+pub(crate) fn handle_nmi(stack_frame: &InterruptStackFrame) {
+    let period = PMC_PERIOD.load(Ordering::Relaxed);
+    if period != 0 {
+        unsafe { arm_counter(period) };
+    }
+    check(stack_frame);
+}
+
+fn timer_check(stack_frame: &InterruptStackFrame) {
+    check(stack_frame);
+}
+
+/// Fires the watchdog if the last heartbeat is older than the timeout.
+fn check(stack_frame: &InterruptStackFrame) {
+    if !ENABLED.load(Ordering::Relaxed) || SUSPENDED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let stalled_for = time::now().saturating_sub(LAST_HEARTBEAT.load(Ordering::Relaxed));
+    if stalled_for <= TIMEOUT_NS.load(Ordering::Relaxed) || FIRED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    // The stalled code may hold the output locks - take them over, we are not going to return to it
+    let lock_states = lock_states();
+    unsafe {
+        crate::serial::SERIAL1.force_unlock();
+        crate::vga_buffer::WRITER.force_unlock();
+    }
+
+    serial_println!("WATCHDOG: no heartbeat for {} ms", stalled_for / 1_000_000);
+    serial_println!("Interrupted RIP: {:?}", stack_frame.instruction_pointer);
+    match CURRENT_TASK.load(Ordering::Relaxed) {
+        NO_TASK => { serial_println!("Current task: none"); }
+        task_id => { serial_println!("Current task: {}", task_id); }
+    }
+    for (name, holder) in lock_states {
+        if let Some(location) = holder {
+            serial_println!("Lock held: {} (acquired at {})", name, location);
+        }
+    }
+
+    panic!("WATCHDOG: kernel stalled\n{:#?}", stack_frame);
+}
+
+/// Returns where each of the kernel's global locks was acquired, or `None` for locks that are free.
+fn lock_states() -> [(&'static str, Option<&'static Location<'static>>); 4] {
+    [
+        ("WRITER", crate::vga_buffer::WRITER.holder()),
+        ("SERIAL1", crate::serial::SERIAL1.holder()),
+        ("PICS", crate::interrupts::PICS.holder()),
+        ("ALLOCATOR", crate::allocator::lock_holder()),
+    ]
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::memory::{self, address_space::{self, AddressSpace}, frame::{self, GlobalFrameAllocator}};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Address in the user half that is mapped differently in every test address space
+const USER_PAGE: u64 = 0x_1000_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { frame::init(&boot_info.memory_map) };
+    allocator::heap_init(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+    address_space::init(&mut GlobalFrameAllocator);
+
+    test_main();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    frame::with_frame_allocator(|allocator| allocator.free_frames())
+}
+
+/// Creates an address space with `USER_PAGE` mapped to a new frame holding `value`.
+fn address_space_with(value: u64) -> (AddressSpace, PhysFrame) {
+    let mut space = AddressSpace::new().expect("out of frames");
+    let frame: PhysFrame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    unsafe { memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u64>().write(value) };
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_PAGE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    unsafe { space.mapper().map_to(page, frame, flags, &mut GlobalFrameAllocator).expect("mapping failed").flush() };
+    (space, frame)
+}
+
+#[test_case]
+fn user_halves_are_separate() {
+    let (first, _) = address_space_with(1);
+    let (second, _) = address_space_with(2);
+    let read = || unsafe { VirtAddr::new(USER_PAGE).as_ptr::<u64>().read_volatile() };
+
+    unsafe { first.switch_to() };
+    assert!(first.is_active());
+    assert_eq!(read(), 1);
+    unsafe { second.switch_to() };
+    assert_eq!(read(), 2);
+    unsafe { first.switch_to() };
+    assert_eq!(read(), 1);
+
+    address_space::switch_to_kernel();
+    assert!(!first.is_active());
+    assert!(!memory::is_mapped(VirtAddr::new(USER_PAGE)));
+}
+
+#[test_case]
+fn kernel_half_is_shared() {
+    let (space, _) = address_space_with(3);
+    let value = Box::new(0x1234u64);
+    unsafe { space.switch_to() };
+    // Heap, stack and the physical memory window are reachable from the new address space
+    assert_eq!(*value, 0x1234);
+    let local = 5u64;
+    assert_eq!(unsafe { core::ptr::read_volatile(&local) }, 5);
+    assert!(memory::is_mapped(memory::phys_to_virt(space.p4_frame().start_address())));
+    address_space::switch_to_kernel();
+}
+
+#[test_case]
+fn teardown_frees_page_tables() {
+    let free = free_frames();
+    let (mut space, _) = address_space_with(4);
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_PAGE));
+    unsafe { memory::unmap_pages(&mut space.mapper(), Page::range(page, page + 1), &mut GlobalFrameAllocator) }
+        .expect("unmapping failed");
+    drop(space);
+    assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn teardown_frees_user_pages() {
+    let free = free_frames();
+    let (space, _) = address_space_with(5);
+    drop(space);
+    assert_eq!(free_frames(), free);
+}
@@ -0,0 +1,142 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, address_space::{self, AddressSpace}, cow, frame::{self, GlobalFrameAllocator}};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Address in the user half of the test address spaces
+const SHARED_PAGE: u64 = 0x_1000_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { frame::init(&boot_info.memory_map) };
+    address_space::init(&mut GlobalFrameAllocator);
+
+    test_main();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    frame::with_frame_allocator(|allocator| allocator.free_frames())
+}
+
+fn ref_count(frame: PhysFrame) -> usize {
+    frame::with_frame_allocator(|allocator| allocator.ref_count(frame))
+}
+
+/// Returns the frame and flags of the level 1 entry for `addr` in the active address space.
+fn leaf(addr: VirtAddr) -> (PhysFrame, PageTableFlags) {
+    let mut result = (PhysAddr::zero(), PageTableFlags::empty());
+    memory::walk_page_tables(addr, |entry| result = (entry.addr, entry.flags));
+    (PhysFrame::containing_address(result.0), result.1)
+}
+
+fn read() -> u64 {
+    unsafe { VirtAddr::new(SHARED_PAGE).as_ptr::<u64>().read_volatile() }
+}
+
+fn write(value: u64) {
+    unsafe { VirtAddr::new(SHARED_PAGE).as_mut_ptr::<u64>().write_volatile(value) }
+}
+
+#[test_case]
+fn writes_copy_shared_pages() {
+    let free = free_frames();
+    let addr = VirtAddr::new(SHARED_PAGE);
+    let mut parent = AddressSpace::new().expect("out of frames");
+    let frame: PhysFrame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    unsafe { memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u64>().write(1) };
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    unsafe { parent.mapper().map_to(page, frame, flags, &mut GlobalFrameAllocator).expect("mapping failed").ignore() };
+
+    let mut child = parent.fork().expect("fork failed");
+    assert_eq!(ref_count(frame), 2);
+
+    unsafe { child.switch_to() };
+    let (child_frame, child_flags) = leaf(addr);
+    assert_eq!(child_frame, frame);
+    assert!(child_flags.contains(cow::COW));
+    assert!(!child_flags.contains(PageTableFlags::WRITABLE));
+    assert_eq!(read(), 1);
+
+    // The first write gives the child its own copy
+    write(2);
+    let (copy, copy_flags) = leaf(addr);
+    assert_ne!(copy, frame);
+    assert!(copy_flags.contains(PageTableFlags::WRITABLE) && !copy_flags.contains(cow::COW));
+    assert_eq!(read(), 2);
+    assert_eq!(ref_count(frame), 1);
+
+    // The parent still sees the original, and as the last reference takes it over without copying
+    unsafe { parent.switch_to() };
+    assert_eq!(read(), 1);
+    write(3);
+    assert_eq!(leaf(addr).0, frame);
+    assert_eq!(read(), 3);
+
+    address_space::switch_to_kernel();
+    child.release_user_pages();
+    parent.release_user_pages();
+    drop(child);
+    drop(parent);
+    assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn read_only_pages_stay_shared() {
+    let addr = VirtAddr::new(SHARED_PAGE);
+    let mut parent = AddressSpace::new().expect("out of frames");
+    let frame: PhysFrame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    unsafe { parent.mapper().map_to(page, frame, flags, &mut GlobalFrameAllocator).expect("mapping failed").ignore() };
+
+    let child = parent.fork().expect("fork failed");
+    unsafe { child.switch_to() };
+    let (child_frame, child_flags) = leaf(addr);
+    assert_eq!(child_frame, frame);
+    assert!(!child_flags.intersects(cow::COW | PageTableFlags::WRITABLE));
+    address_space::switch_to_kernel();
+
+    // Dropping an address space drops its references
+    drop(child);
+    assert_eq!(ref_count(frame), 1);
+    drop(parent);
+    assert_eq!(ref_count(frame), 0);
+}
+
+#[test_case]
+fn faults_while_the_frame_allocator_is_held_are_not_resolved() {
+    let addr = VirtAddr::new(SHARED_PAGE);
+    let mut parent = AddressSpace::new().expect("out of frames");
+    let frame: PhysFrame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    unsafe { parent.mapper().map_to(page, frame, flags, &mut GlobalFrameAllocator).expect("mapping failed").ignore() };
+    let child = parent.fork().expect("fork failed");
+
+    unsafe { child.switch_to() };
+    let error_code = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    assert!(!frame::with_frame_allocator(|_| cow::handle_page_fault(addr, error_code)));
+    assert!(leaf(addr).1.contains(cow::COW));
+    assert!(cow::handle_page_fault(addr, error_code));
+    assert!(leaf(addr).1.contains(PageTableFlags::WRITABLE));
+    address_space::switch_to_kernel();
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::interrupts::{deferred, irq};
+use x86_64::structures::idt::InterruptStackFrame;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, frame::GlobalFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map) };
+    let mut frame_allocator = GlobalFrameAllocator;
+    allocator::heap_init(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    deferred::init();
+
+    test_main();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+static SUM: AtomicUsize = AtomicUsize::new(0);
+
+fn add_to_sum(value: usize) {
+    SUM.store(SUM.load(Ordering::Relaxed) + value, Ordering::Relaxed);
+}
+
+#[test_case]
+fn run_pending_executes_in_order() {
+    SUM.store(0, Ordering::Relaxed);
+    deferred::schedule(add_to_sum, 1).expect("scheduling failed");
+    deferred::schedule(add_to_sum, 2).expect("scheduling failed");
+    assert!(deferred::has_pending());
+
+    deferred::run_pending();
+    assert!(!deferred::has_pending());
+    assert_eq!(SUM.load(Ordering::Relaxed), 3);
+}
+
+static IRQ_WORK_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_irq_work(_: usize) {
+    // Deferred work must run with interrupts enabled
+    assert!(x86_64::instructions::interrupts::are_enabled());
+    IRQ_WORK_RUNS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn schedule_from_timer(_stack_frame: &InterruptStackFrame) {
+    let _ = deferred::schedule(count_irq_work, 0);
+}
+
+#[test_case]
+fn work_scheduled_from_irq_runs_after_eoi() {
+    let before = deferred::stats().executed;
+    let id = irq::register_irq(irq::TIMER, schedule_from_timer).expect("registering handler failed");
+    while IRQ_WORK_RUNS.load(Ordering::Relaxed) == 0 {
+        x86_64::instructions::hlt();
+    }
+    irq::unregister_irq(id).expect("unregistering handler failed");
+
+    assert!(deferred::stats().executed > before);
+}
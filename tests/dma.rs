@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, dma::DmaBuffer, frame::{self, GlobalFrameAllocator, Zone}};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { frame::init(&boot_info.memory_map) };
+
+    test_main();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn zone_allocations_stay_below_the_limit() {
+    frame::with_frame_allocator(|allocator| {
+        for zone in [Zone::Dma16, Zone::Dma32] {
+            let start = allocator.allocate_in_zone(zone, 8, 8).expect("zone exhausted");
+            assert!((start + 8).start_address().as_u64() <= zone.limit());
+            assert_eq!(start.start_address().as_u64() % (8 * 4096), 0);
+            unsafe { allocator.deallocate_contiguous(start, 8) };
+        }
+    });
+}
+
+#[test_case]
+fn single_frames_spare_the_dma16_zone() {
+    let dma16_free = frame::with_frame_allocator(|allocator| allocator.free_frames_in(Zone::Dma16));
+    let frame: PhysFrame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    if frame::with_frame_allocator(|allocator| allocator.free_frames_in(Zone::Dma32)) > 0 {
+        assert!(frame.start_address().as_u64() >= Zone::Dma16.limit());
+        assert_eq!(frame::with_frame_allocator(|allocator| allocator.free_frames_in(Zone::Dma16)), dma16_free);
+    }
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn dma_buffer_is_contiguous_and_freed() {
+    let free = frame::with_frame_allocator(|allocator| allocator.free_frames());
+    let mut buffer = DmaBuffer::new(10_000, Zone::Dma16, 0x1_0000).expect("DMA16 zone exhausted");
+    assert_eq!(frame::with_frame_allocator(|allocator| allocator.free_frames()), free - 3);
+    assert_eq!(buffer.len(), 10_000);
+    assert!(buffer.phys_addr().as_u64() + 10_000 <= Zone::Dma16.limit());
+    assert_eq!(buffer.phys_addr().as_u64() % 0x1_0000, 0);
+    assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+
+    // The kernel's writes are visible at the physical address the device uses
+    buffer.as_mut_slice()[9_999] = 0x5a;
+    let last = memory::phys_to_virt(buffer.phys_addr() + 9_999u64);
+    assert_eq!(unsafe { last.as_ptr::<u8>().read_volatile() }, 0x5a);
+
+    drop(buffer);
+    assert_eq!(frame::with_frame_allocator(|allocator| allocator.free_frames()), free);
+}
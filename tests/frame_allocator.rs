@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, frame::{self, GlobalFrameAllocator}};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Unused virtual address for the mapping tests
+const TEST_PAGE: u64 = 0x_1000_0000_0000;
+
+entry_point!(main);
+
+/// Page table for the tests to map pages with
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    *MAPPER.lock() = Some(unsafe { memory::init(phys_mem_offset) });
+    unsafe { frame::init(&boot_info.memory_map) };
+
+    test_main();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn counts_usable_memory() {
+    let (free, usable) = frame::with_frame_allocator(|allocator| (allocator.free_frames(), allocator.usable_frames()));
+    assert!(free > 0);
+    assert!(free < usable, "the bitmap itself takes up frames");
+}
+
+#[test_case]
+fn freed_frames_are_reused() {
+    let free = frame::with_frame_allocator(|allocator| allocator.free_frames());
+    let first: PhysFrame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    let second: PhysFrame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    assert_ne!(first, second);
+    assert_eq!(frame::with_frame_allocator(|allocator| allocator.free_frames()), free - 2);
+
+    unsafe { GlobalFrameAllocator.deallocate_frame(first) };
+    assert_eq!(GlobalFrameAllocator.allocate_frame(), Some(first));
+
+    unsafe {
+        GlobalFrameAllocator.deallocate_frame(first);
+        GlobalFrameAllocator.deallocate_frame(second);
+    }
+    assert_eq!(frame::with_frame_allocator(|allocator| allocator.free_frames()), free);
+}
+
+#[test_case]
+fn contiguous_allocation() {
+    frame::with_frame_allocator(|allocator| {
+        let free = allocator.free_frames();
+        let start = allocator.allocate_contiguous(16, 16).expect("no contiguous frames");
+        assert_eq!(start.start_address().as_u64() % (16 * 4096), 0);
+        assert_eq!(allocator.free_frames(), free - 16);
+
+        // None of the allocated frames is handed out again
+        let single = allocator.allocate_frame().unwrap();
+        assert!(single < start || single >= start + 16);
+        unsafe { allocator.deallocate_frame(single) };
+
+        unsafe { allocator.deallocate_contiguous(start, 16) };
+        assert_eq!(allocator.free_frames(), free);
+        assert_eq!(allocator.allocate_contiguous(16, 16), Some(start));
+        unsafe { allocator.deallocate_contiguous(start, 16) };
+    });
+}
+
+#[test_case]
+fn unmapping_returns_frames() {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TEST_PAGE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let frame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator).expect("mapping failed").flush() };
+    unsafe { page.start_address().as_mut_ptr::<u64>().write_volatile(42) };
+
+    // Measured after mapping, as the page tables created for the mapping stay allocated
+    let free = frame::with_frame_allocator(|allocator| allocator.free_frames());
+    unsafe { memory::unmap_pages(mapper, Page::range(page, page + 1), &mut GlobalFrameAllocator).expect("unmapping failed") };
+    assert!(!memory::is_mapped(page.start_address()));
+    assert_eq!(frame::with_frame_allocator(|allocator| allocator.free_frames()), free + 1);
+}
@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::gdt;
+use spin::Mutex;
+use rust_os::memory::{self, frame::GlobalFrameAllocator};
+use rust_os::memory::stack::{StackAllocator, STACK_REGION_SIZE, STACK_REGION_START};
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// Page table and frame allocator for the tests to map stacks with
+static PAGING: Mutex<Option<(OffsetPageTable<'static>, GlobalFrameAllocator)>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map) };
+    let mut frame_allocator = GlobalFrameAllocator;
+    rust_os::init_stacks(&mut mapper, &mut frame_allocator)
+        .expect("stack initialization failed");
+
+    *PAGING.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn double_fault_stack_is_guarded() {
+    let stack_end = gdt::ist_stack(gdt::DOUBLE_FAULT_IST_INDEX);
+    let region = STACK_REGION_START..STACK_REGION_START + STACK_REGION_SIZE;
+    assert!(region.contains(&(stack_end.as_u64() - 1)));
+    assert!(memory::is_mapped(stack_end - 1u64));
+}
+
+#[test_case]
+fn stacks_are_separated_by_guard_pages() {
+    let mut paging = PAGING.lock();
+    let (mapper, frame_allocator) = paging.as_mut().unwrap();
+    // A private region, so the test doesn't depend on what the kernel has allocated
+    let mut allocator = StackAllocator::new(VirtAddr::new(STACK_REGION_START + STACK_REGION_SIZE / 2), 0x10000);
+
+    let first = allocator.allocate(2, mapper, frame_allocator).expect("allocating stack failed");
+    let second = allocator.allocate(3, mapper, frame_allocator).expect("allocating stack failed");
+    assert_eq!(first.end() - first.start(), 2 * 4096);
+    assert_eq!(second.end() - second.start(), 3 * 4096);
+
+    for stack in [first, second] {
+        assert!(!memory::is_mapped(stack.guard_page().start_address()));
+        assert!(memory::is_mapped(stack.start()));
+        assert!(memory::is_mapped(stack.end() - 1u64));
+        unsafe { (stack.end() - 8u64).as_mut_ptr::<u64>().write_volatile(0xdead_beef) };
+    }
+    assert_eq!(second.guard_page().start_address(), first.end());
+}
+
+#[test_case]
+fn region_exhaustion_is_reported() {
+    let mut paging = PAGING.lock();
+    let (mapper, frame_allocator) = paging.as_mut().unwrap();
+    let mut allocator = StackAllocator::new(VirtAddr::new(STACK_REGION_START + STACK_REGION_SIZE / 2 + 0x10000), 0x2000);
+    assert!(allocator.allocate(2, mapper, frame_allocator).is_err());
+}
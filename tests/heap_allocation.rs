@@ -15,13 +15,14 @@ entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use rust_os::allocator;
-    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use rust_os::memory::{self, frame::GlobalFrameAllocator};
     use x86_64::VirtAddr;
     
     rust_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    unsafe { memory::frame::init(&boot_info.memory_map) };
+    let mut frame_allocator = GlobalFrameAllocator;
     allocator::heap_init(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
 
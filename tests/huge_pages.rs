@@ -0,0 +1,120 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, frame::{self, GlobalFrameAllocator}};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Unused, 1 GiB aligned virtual addresses for the mapping tests
+const TEST_BUFFER: u64 = 0x_1000_0000_0000;
+const TEST_WINDOW: u64 = 0x_1000_4000_0000;
+
+entry_point!(main);
+
+/// Page table and physical memory offset for the tests
+static PAGING: Mutex<Option<(OffsetPageTable<'static>, VirtAddr)>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { frame::init(&boot_info.memory_map) };
+    *PAGING.lock() = Some((mapper, phys_mem_offset));
+
+    test_main();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    let offset = PAGING.lock().as_ref().unwrap().1;
+    unsafe { memory::translate_addr(addr, offset) }
+}
+
+#[test_case]
+fn translate_physical_memory_window() {
+    // The bootloader may map physical memory with huge pages
+    let phys = PhysAddr::new(0x20_1234);
+    assert_eq!(translate(memory::phys_to_virt(phys)), Some(phys));
+}
+
+#[test_case]
+fn map_2mib_buffer() {
+    let mut paging = PAGING.lock();
+    let (mapper, _) = paging.as_mut().unwrap();
+    let start = Page::<Size2MiB>::containing_address(VirtAddr::new(TEST_BUFFER));
+    let pages = Page::range(start, start + 2);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::map_new(mapper, pages, flags, &mut GlobalFrameAllocator).expect("mapping failed");
+    drop(paging);
+
+    let last = VirtAddr::new(TEST_BUFFER + 2 * Size2MiB::SIZE - 8);
+    unsafe {
+        VirtAddr::new(TEST_BUFFER).as_mut_ptr::<u64>().write_volatile(1);
+        last.as_mut_ptr::<u64>().write_volatile(2);
+    }
+    let first_frame = translate(VirtAddr::new(TEST_BUFFER)).unwrap();
+    assert!(first_frame.is_aligned(Size2MiB::SIZE));
+    assert_eq!(translate(VirtAddr::new(TEST_BUFFER + 0x1234)), Some(first_frame + 0x1234u64));
+    assert!(memory::is_mapped(last));
+
+    let free = frame::with_frame_allocator(|allocator| allocator.free_frames());
+    let mut paging = PAGING.lock();
+    let (mapper, _) = paging.as_mut().unwrap();
+    unsafe { memory::unmap_pages(mapper, pages, &mut GlobalFrameAllocator).expect("unmapping failed") };
+    assert!(!memory::is_mapped(last));
+    assert_eq!(frame::with_frame_allocator(|allocator| allocator.free_frames()), free + 2 * 512);
+}
+
+#[test_case]
+fn map_physical_range_uses_huge_pages() {
+    let mut paging = PAGING.lock();
+    let (mapper, _) = paging.as_mut().unwrap();
+    let frame: PhysFrame<Size2MiB> = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    let phys = frame.start_address();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    // One 2 MiB page followed by two 4 KiB pages
+    let size = Size2MiB::SIZE + 2 * Size4KiB::SIZE;
+    unsafe {
+        memory::map_physical_range(mapper, VirtAddr::new(TEST_WINDOW), phys, size, flags, &mut GlobalFrameAllocator)
+            .expect("mapping failed")
+    };
+    drop(paging);
+
+    let mut huge = false;
+    memory::walk_page_tables(VirtAddr::new(TEST_WINDOW), |entry| {
+        huge |= entry.level == 2 && entry.flags.contains(PageTableFlags::HUGE_PAGE);
+    });
+    assert!(huge, "window start isn't mapped with a 2 MiB page");
+    let tail = VirtAddr::new(TEST_WINDOW) + Size2MiB::SIZE + 0x1008u64;
+    assert_eq!(translate(tail), Some(phys + Size2MiB::SIZE + 0x1008u64));
+
+    // Remove the window without freeing what it maps, then free the frame
+    let mut paging = PAGING.lock();
+    let (mapper, _) = paging.as_mut().unwrap();
+    let huge_page = Page::<Size2MiB>::containing_address(VirtAddr::new(TEST_WINDOW));
+    let (unmapped, flush) = mapper.unmap(huge_page).expect("unmapping failed");
+    flush.flush();
+    assert_eq!(unmapped, frame);
+    let first_small = Page::<Size4KiB>::containing_address(VirtAddr::new(TEST_WINDOW) + Size2MiB::SIZE);
+    for page in Page::range(first_small, first_small + 2) {
+        mapper.unmap(page).expect("unmapping failed").1.flush();
+    }
+    drop(paging);
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    assert!(!memory::is_mapped(VirtAddr::new(TEST_WINDOW)));
+    assert!(!memory::is_mapped(tail));
+}
@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::MemoryRegionType;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, frame::{self, GlobalFrameAllocator}, stats::MemoryStats};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Unused virtual address whose level 4 entry is empty
+const TEST_PAGE: u64 = 0x_1000_0000_0000;
+
+entry_point!(main);
+
+/// Page table for the tests to map pages with
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    *MAPPER.lock() = Some(unsafe { memory::init(phys_mem_offset) });
+    unsafe { frame::init(&boot_info.memory_map) };
+
+    test_main();
+    MemoryStats::collect().print();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn summary_adds_up() {
+    let stats = MemoryStats::collect();
+    assert_eq!(stats.total, stats.usable + stats.kernel + stats.boot + stats.reserved);
+    assert_eq!(stats.usable, stats.frames_of_type(MemoryRegionType::Usable));
+    assert!(stats.kernel > 0);
+    assert!(stats.free < stats.usable);
+    assert!(stats.page_table_frames > 0);
+}
+
+#[test_case]
+fn counters_track_allocations() {
+    let before = MemoryStats::collect();
+    let single: PhysFrame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    let run = frame::with_frame_allocator(|allocator| allocator.allocate_contiguous(4, 4)).expect("no contiguous frames");
+    assert_eq!(MemoryStats::collect().in_use(), before.in_use() + 5);
+
+    unsafe {
+        GlobalFrameAllocator.deallocate_frame(single);
+        frame::with_frame_allocator(|allocator| allocator.deallocate_contiguous(run, 4));
+    }
+    let after = MemoryStats::collect();
+    assert_eq!(after.allocated - before.allocated, 5);
+    assert_eq!(after.freed - before.freed, 5);
+    assert_eq!(after.free, before.free);
+}
+
+#[test_case]
+fn page_tables_are_counted() {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let before = MemoryStats::collect();
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TEST_PAGE));
+    let pages = Page::range(page, page + 1);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory::map_new(mapper, pages, flags, &mut GlobalFrameAllocator).expect("mapping failed");
+
+    // A level 3, 2 and 1 table, plus the page itself
+    let after = MemoryStats::collect();
+    assert_eq!(after.page_table_frames, before.page_table_frames + 3);
+    assert_eq!(after.allocated - before.allocated, 4);
+}
@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::interrupts::lapic;
+use rust_os::memory::{self, frame::{self, GlobalFrameAllocator}, mmio::{self, CachePolicy, Volatile}, vma};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Local APIC version register
+const LAPIC_VERSION: usize = 0x30;
+
+/// Legacy VGA graphics memory, unused in text mode
+const VGA_GRAPHICS: u64 = 0xa_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { frame::init(&boot_info.memory_map) };
+    allocator::heap_init(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+    vma::init();
+    mmio::init();
+
+    test_main();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn page_flags(addr: VirtAddr) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    memory::walk_page_tables(addr, |entry| flags = entry.flags);
+    flags
+}
+
+#[test_case]
+fn pat_has_write_combining() {
+    let pat_supported = core::arch::x86_64::__cpuid(1).edx & (1 << 16) != 0;
+    if pat_supported {
+        let pat = unsafe { Msr::new(0x277).read() };
+        assert_eq!((pat >> 8) & 0xff, 0x01);
+        assert_eq!(CachePolicy::WriteCombining.flags(), PageTableFlags::WRITE_THROUGH);
+    } else {
+        assert_eq!(CachePolicy::WriteCombining.flags(), CachePolicy::Uncached.flags());
+    }
+}
+
+#[test_case]
+fn map_local_apic_uncached() {
+    let phys = lapic::base_address();
+    let region = unsafe { mmio::map_mmio(phys, 0x400, CachePolicy::Uncached) }.expect("mapping failed");
+    let base = region.base();
+    assert_eq!(region.phys(), phys);
+    assert!(page_flags(base).contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+    assert!(vma::KERNEL_VMAS.lock().find(base).is_some());
+
+    // Bits 0-7 hold the version, integrated APICs report 0x10 or higher
+    let version: u32 = region.read(LAPIC_VERSION);
+    assert!(version & 0xff >= 0x10, "unexpected version {:#x}", version);
+    let register: &Volatile<u32> = region.register(LAPIC_VERSION);
+    assert_eq!(register.read(), version);
+
+    drop(region);
+    assert!(!memory::is_mapped(base));
+    assert!(vma::KERNEL_VMAS.lock().find(base).is_none());
+}
+
+#[test_case]
+fn map_write_combining() {
+    let region = unsafe { mmio::map_mmio(PhysAddr::new(VGA_GRAPHICS), 0x1000, CachePolicy::WriteCombining) }
+        .expect("mapping failed");
+    let flags = page_flags(region.base());
+    let pat_supported = core::arch::x86_64::__cpuid(1).edx & (1 << 16) != 0;
+    if pat_supported {
+        assert!(flags.contains(PageTableFlags::WRITE_THROUGH));
+        assert!(!flags.contains(PageTableFlags::NO_CACHE));
+    } else {
+        assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+    }
+}
+
+#[test_case]
+fn oversized_ranges_are_rejected() {
+    let phys = PhysAddr::new(0x000f_ffff_ffff_f000);
+    assert!(matches!(unsafe { mmio::map_mmio(phys, 0x2000, CachePolicy::Uncached) }, Err(vma::VmaError::OutOfSpace)));
+    assert!(matches!(unsafe { mmio::map_mmio(phys, u64::MAX, CachePolicy::Uncached) }, Err(vma::VmaError::OutOfSpace)));
+}
+
+#[test_case]
+fn lapic_uses_mmio_mapping() {
+    lapic::init();
+    let version = lapic::read(LAPIC_VERSION);
+    assert!(version & 0xff >= 0x10);
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use rust_os::{exit_qemu, gdt, serial_print, serial_println, QemuExitCode};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+lazy_static! {
+    /// Test IDT with the NMI handler on its IST stack. A double fault means the NMI handler ran on the broken stack.
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_fn(test_nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_nmi_handler(_stack_frame: InterruptStackFrame) {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+
+    // The handler must run on the IST stack
+    let stack_end = gdt::ist_stack(gdt::NMI_IST_INDEX).as_u64();
+    if rsp < stack_end && rsp >= stack_end - gdt::IST_STACK_PAGES * 4096 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: handler ran on stack {:#x}, IST stack ends at {:#x}\n", rsp, stack_end);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    rust_os::idle_loop();
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(_stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: NMI escalated to a double fault\n");
+    exit_qemu(QemuExitCode::Failed);
+
+    rust_os::idle_loop();
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    serial_print!("nmi_stack::nmi_stack...\t");
+
+    rust_os::gdt::init();
+    init_test_idt();
+
+    // Point the stack at the unmapped null page, then raise an NMI
+    unsafe {
+        asm!(
+            "mov rsp, 0x1000",
+            "int 2",
+            options(noreturn)
+        );
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use rust_os::memory::{self, frame::{self, GlobalFrameAllocator}, inspect};
+use rust_os::{allocator, exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+/// Address of the code placed on the heap
+static HEAP_CODE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Test IDT with a page fault handler that expects an instruction fetch from the heap.
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(_stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let addr = Cr2::read();
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && addr.as_u64() == HEAP_CODE.load(Ordering::Relaxed) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault at {:#x} ({:?})\n", addr.as_u64(), error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    rust_os::idle_loop();
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("nx_heap::execute_from_heap...\t");
+
+    rust_os::gdt::init();
+    TEST_IDT.load();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { frame::init(&boot_info.memory_map) };
+    allocator::heap_init(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+    memory::protection::init();
+
+    let violations = inspect::check_active("W^X", inspect::writable_and_executable);
+    if violations > 0 {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {} ranges are writable and executable\n", violations);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    // A lone `ret` instruction
+    let code = Box::new([0xc3u8; 16]);
+    HEAP_CODE.store(code.as_ptr() as u64, Ordering::Relaxed);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: executed code on the heap\n");
+    exit_qemu(QemuExitCode::Failed);
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, frame::{self, GlobalFrameAllocator}, inspect::{self, MappedRange}};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Unused virtual address for the mapping tests
+const TEST_PAGES: u64 = 0x_1000_0000_0000;
+
+entry_point!(main);
+
+/// Page table for the tests to map pages with
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    *MAPPER.lock() = Some(unsafe { memory::init(phys_mem_offset) });
+    unsafe { frame::init(&boot_info.memory_map) };
+
+    inspect::dump_active();
+    test_main();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Returns the range containing `addr`.
+fn range_containing(addr: VirtAddr) -> Option<MappedRange> {
+    let mut found = None;
+    inspect::for_each_active_range(|range| {
+        if range.contains(addr) {
+            found = Some(*range);
+        }
+    });
+    found
+}
+
+#[test_case]
+fn ranges_are_sorted_and_disjoint() {
+    let mut previous_last = None;
+    let mut count = 0;
+    inspect::for_each_active_range(|range| {
+        assert!(range.size > 0);
+        if let Some(last) = previous_last {
+            assert!(range.start > last, "{} overlaps the previous range", range);
+        }
+        previous_last = Some(range.last());
+        count += 1;
+    });
+    assert!(count > 0);
+}
+
+#[test_case]
+fn contiguous_pages_are_merged() {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let start: PhysFrame = frame::with_frame_allocator(|allocator| allocator.allocate_contiguous(3, 1))
+        .expect("no contiguous frames");
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TEST_PAGES));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { memory::map_contiguous(mapper, page, start, 3, flags, &mut GlobalFrameAllocator).expect("mapping failed") };
+
+    // A fourth page whose frame doesn't continue the others starts a new range
+    let other: PhysFrame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    let other = if other == start + 3 { GlobalFrameAllocator.allocate_frame().unwrap() } else { other };
+    unsafe { memory::map_contiguous(mapper, page + 3, other, 1, flags, &mut GlobalFrameAllocator).expect("mapping failed") };
+
+    let range = range_containing(page.start_address()).expect("test pages not found");
+    assert_eq!(range.start, page.start_address());
+    assert_eq!(range.size, 3 * 4096);
+    assert_eq!(range.phys, start.start_address());
+    assert_eq!(range.flags, flags);
+    assert_eq!(range_containing((page + 3).start_address()).unwrap().size, 4096);
+
+    // The pages are writable and mapped without NO_EXECUTE
+    assert!(inspect::check_active("W^X", inspect::writable_and_executable) >= 2);
+}
+
+#[test_case]
+fn effective_flags_combine_levels() {
+    // The physical memory window is writable but never user accessible
+    let range = range_containing(memory::phys_to_virt(x86_64::PhysAddr::new(0x1000))).expect("window not mapped");
+    assert!(range.is_writable());
+    assert!(!range.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::gdt;
+use rust_os::memory::{self, frame::GlobalFrameAllocator};
+use rust_os::memory::stack::{self, StackAllocator, STACK_REGION_SIZE, STACK_REGION_START};
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::VirtAddr;
+
+/// Stack usage budgets - exceeding one fails the test, so regressions in stack usage show up
+const BOOT_STACK_BUDGET: u64 = 256 * 1024;
+const BREAKPOINT_BUDGET: u64 = 16 * 1024;
+
+entry_point!(main);
+
+/// Page table and frame allocator for the tests to map stacks with
+static PAGING: Mutex<Option<(OffsetPageTable<'static>, GlobalFrameAllocator)>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map) };
+    let mut frame_allocator = GlobalFrameAllocator;
+    rust_os::init_stacks(&mut mapper, &mut frame_allocator)
+        .expect("stack initialization failed");
+    *PAGING.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    stack::print_usage();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn boot_stack_within_budget() {
+    let usage = stack::usage("boot").expect("boot stack is not tracked");
+    assert!(usage.max_used > 0);
+    assert!(usage.max_used <= BOOT_STACK_BUDGET, "boot stack used {} bytes", usage.max_used);
+}
+
+#[test_case]
+fn unused_stacks_report_no_usage() {
+    for name in ["double fault", "nmi", "machine check", "privilege", "syscall"] {
+        let usage = stack::usage(name).expect("stack is not tracked");
+        assert_eq!(usage.max_used, 0, "{} stack", name);
+    }
+    let double_fault = stack::usage("double fault").unwrap();
+    assert_eq!(double_fault.size, gdt::IST_STACK_PAGES * 4096);
+}
+
+#[test_case]
+fn usage_follows_deepest_write() {
+    let mut paging = PAGING.lock();
+    let (mapper, frame_allocator) = paging.as_mut().unwrap();
+    // A private region, so the test doesn't depend on what the kernel has allocated
+    let mut allocator = StackAllocator::new(VirtAddr::new(STACK_REGION_START + STACK_REGION_SIZE / 2), 0x10000);
+    let test_stack = allocator.allocate(2, mapper, frame_allocator).expect("allocating stack failed");
+    assert_eq!(test_stack.max_used(), 0);
+
+    unsafe { (test_stack.end() - 0x100u64).as_mut_ptr::<u64>().write_volatile(0) };
+    assert_eq!(test_stack.max_used(), 0x100);
+    unsafe { (test_stack.end() - 0x40u64).as_mut_ptr::<u64>().write_volatile(0) };
+    assert_eq!(test_stack.max_used(), 0x100);
+
+    assert!(stack::track("test", test_stack));
+    assert_eq!(stack::usage("test").map(|usage| usage.max_used), Some(0x100));
+}
+
+#[test_case]
+fn breakpoint_handler_within_budget() {
+    // Forget the deeper usage of the boot, so only the handler below this frame is measured
+    let in_use = stack::repaint_boot_stack().expect("not running on the boot stack");
+    x86_64::instructions::interrupts::int3();
+    let used_by_breakpoint = stack::usage("boot").unwrap().max_used - in_use;
+    assert!(used_by_breakpoint <= BREAKPOINT_BUDGET, "breakpoint handler used {} bytes", used_by_breakpoint);
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::memory::{self, frame::{self, GlobalFrameAllocator}};
+use rust_os::syscall::{dispatch, SyscallArgs, SyscallError, FD_SERIAL, SYS_WRITE};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// User accessible page in the user half, followed by a page only the kernel may access
+const USER_PAGE: u64 = 0x_1000_0000_0000;
+const SUPERVISOR_PAGE: u64 = USER_PAGE + 0x1000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { frame::init(&boot_info.memory_map) };
+    allocator::heap_init(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    map_page(&mut mapper, USER_PAGE, writable | PageTableFlags::USER_ACCESSIBLE);
+    map_page(&mut mapper, SUPERVISOR_PAGE, writable);
+
+    test_main();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn map_page(mapper: &mut impl Mapper<Size4KiB>, address: u64, flags: PageTableFlags) {
+    let page = Page::containing_address(VirtAddr::new(address));
+    let frame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator).expect("mapping failed").flush() };
+}
+
+/// Copies `bytes` to the end of the user page and returns their address.
+fn user_buffer(bytes: &[u8]) -> u64 {
+    let address = SUPERVISOR_PAGE - bytes.len() as u64;
+    unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), address as *mut u8, bytes.len()) };
+    address
+}
+
+fn write(address: u64, len: usize) -> Result<u64, SyscallError> {
+    dispatch(SYS_WRITE, &SyscallArgs([FD_SERIAL, address, len as u64, 0, 0, 0]))
+}
+
+#[test_case]
+fn user_buffers_are_written() {
+    let message = b"user buffer ";
+    assert_eq!(write(user_buffer(message), message.len()), Ok(message.len() as u64));
+}
+
+#[test_case]
+fn kernel_heap_is_rejected() {
+    let secret = Box::new(*b"kernel heap data");
+    assert_eq!(write(secret.as_ptr() as u64, secret.len()), Err(SyscallError::BadAddress));
+}
+
+#[test_case]
+fn supervisor_pages_in_the_user_half_are_rejected() {
+    assert_eq!(write(SUPERVISOR_PAGE, 16), Err(SyscallError::BadAddress));
+
+    // Starts in the user page but ends in the supervisor page
+    let address = user_buffer(b"abcd");
+    assert_eq!(write(address, 8), Err(SyscallError::BadAddress));
+}
+
+#[test_case]
+fn invalid_utf8_is_rejected() {
+    let invalid = [0xffu8, 0xfe];
+    assert_eq!(write(user_buffer(&invalid), invalid.len()), Err(SyscallError::InvalidUtf8));
+}
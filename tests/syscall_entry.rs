@@ -0,0 +1,187 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use rust_os::memory::{self, frame::GlobalFrameAllocator};
+use rust_os::syscall::{self, SyscallError, FD_SERIAL, SYS_UPTIME, SYS_WRITE};
+use rust_os::{exit_qemu, gdt, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// User code page, with the message and the results in its second half, and the top of the user stack
+const USER_CODE: u64 = 0x1000_0000_0000;
+const MESSAGE: u64 = USER_CODE + 0x800;
+const RESULTS: u64 = USER_CODE + 0xc00;
+const USER_STACK_TOP: u64 = 0x1000_0001_0000;
+
+const MESSAGE_TEXT: &[u8] = b"syscall ";
+
+/// Kernel data the user code tries to read
+static KERNEL_SECRET: [u8; 8] = *b"secret!!";
+
+/// Address of the `hlt` that ends the user code
+static END: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Test IDT: the general protection fault from the final `hlt` means the system calls returned
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.general_protection_fault.set_handler_fn(test_general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+/// Writes machine code into the user code page.
+struct Assembler {
+    next: u64,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            unsafe { (self.next as *mut u8).write_volatile(byte) };
+            self.next += 1;
+        }
+    }
+
+    /// `mov r32, imm32` for eax (0), edx (2) or edi (7)
+    fn mov_imm32(&mut self, register: u8, value: u32) {
+        self.emit(&[0xb8 + register]);
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// `movabs rsi, imm64`
+    fn mov_rsi(&mut self, value: u64) {
+        self.emit(&[0x48, 0xbe]);
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// `syscall`, then `movabs [address], rax`
+    fn syscall_and_store(&mut self, address: u64) {
+        self.emit(&[0x0f, 0x05]);
+        self.store_rax(address);
+    }
+
+    fn store_rax(&mut self, address: u64) {
+        self.emit(&[0x48, 0xa3]);
+        self.emit(&address.to_le_bytes());
+    }
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("syscall_entry::syscall_entry...\t");
+
+    rust_os::gdt::init();
+    rust_os::interrupts::pic::init();       // Keep hardware interrupts masked - the test IDT has no handlers for them
+    TEST_IDT.load();
+    syscall::entry::init();
+
+    let mut mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    unsafe { memory::frame::init(&boot_info.memory_map) };
+    let mut frame_allocator = GlobalFrameAllocator;
+    rust_os::init_stacks(&mut mapper, &mut frame_allocator)
+        .expect("stack initialization failed");
+
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    map_page(&mut mapper, &mut frame_allocator, USER_CODE, user);
+    map_page(&mut mapper, &mut frame_allocator, USER_STACK_TOP - 4096, user);
+    unsafe { core::ptr::copy_nonoverlapping(MESSAGE_TEXT.as_ptr(), MESSAGE as *mut u8, MESSAGE_TEXT.len()) };
+
+    let mut code = Assembler { next: USER_CODE };
+    // write(FD_SERIAL, MESSAGE, len), then save rdi to check the entry path restores it
+    code.mov_imm32(0, SYS_WRITE as u32);
+    code.mov_imm32(7, FD_SERIAL as u32);
+    code.mov_rsi(MESSAGE);
+    code.mov_imm32(2, MESSAGE_TEXT.len() as u32);
+    code.syscall_and_store(RESULTS);
+    code.emit(&[0x48, 0x89, 0xf8]);         // mov rax, rdi
+    code.store_rax(RESULTS + 8);
+    // write(FD_SERIAL, KERNEL_SECRET, 8) must fail
+    code.mov_imm32(0, SYS_WRITE as u32);
+    code.mov_rsi(KERNEL_SECRET.as_ptr() as u64);
+    code.mov_imm32(2, KERNEL_SECRET.len() as u32);
+    code.syscall_and_store(RESULTS + 16);
+    // uptime()
+    code.mov_imm32(0, SYS_UPTIME as u32);
+    code.syscall_and_store(RESULTS + 24);
+    END.store(code.next, Ordering::Relaxed);
+    code.emit(&[0xf4]);                     // hlt
+
+    unsafe { rust_os::user::enter_user_mode(VirtAddr::new(USER_CODE), VirtAddr::new(USER_STACK_TOP)) };
+}
+
+fn map_page(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    address: u64,
+    flags: PageTableFlags,
+) {
+    let page = Page::containing_address(VirtAddr::new(address));
+    let frame = frame_allocator.allocate_frame().expect("out of frames");
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator).expect("mapping failed").flush() };
+}
+
+fn result(index: u64) -> u64 {
+    unsafe { ((RESULTS + index * 8) as *const u64).read_volatile() }
+}
+
+extern "x86-interrupt" fn test_general_protection_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) {
+    let from_user = stack_frame.code_segment == u64::from(gdt::selectors().user_code_selector.0);
+    if !from_user || stack_frame.instruction_pointer.as_u64() != END.load(Ordering::Relaxed) {
+        fail("general protection fault not raised by the final hlt");
+    }
+    if stack_frame.stack_pointer.as_u64() != USER_STACK_TOP {
+        fail("user stack pointer not restored");
+    }
+
+    if syscall::decode_result(result(0)) != Ok(MESSAGE_TEXT.len() as u64) {
+        fail("write from user memory failed");
+    }
+    if result(1) != FD_SERIAL {
+        fail("rdi not preserved across the system call");
+    }
+    if syscall::decode_result(result(2)) != Err(SyscallError::BadAddress) {
+        fail("write from kernel memory was not rejected");
+    }
+    if syscall::decode_result(result(3)).is_err() {
+        fail("uptime failed");
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    rust_os::idle_loop();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(_stack_frame: InterruptStackFrame, _error_code: PageFaultErrorCode) {
+    fail("page fault");
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(_stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    fail("double fault");
+}
+
+fn fail(reason: &str) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", reason);
+    exit_qemu(QemuExitCode::Failed);
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
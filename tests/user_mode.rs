@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rust_os::memory::{self, frame::GlobalFrameAllocator};
+use rust_os::{exit_qemu, gdt, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// User code page and the top of the user stack, in a level 4 entry the kernel doesn't use
+const USER_CODE: u64 = 0x1000_0000_0000;
+const USER_STACK_TOP: u64 = 0x1000_0001_0000;
+
+/// `hlt` is privileged, so executing it in user mode raises a general protection fault
+const HLT: u8 = 0xf4;
+
+lazy_static! {
+    /// Test IDT: the general protection fault from user mode means success, anything else is a failure
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.general_protection_fault.set_handler_fn(test_general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("user_mode::user_mode...\t");
+
+    rust_os::gdt::init();
+    rust_os::interrupts::pic::init();       // Keep hardware interrupts masked - the test IDT has no handlers for them
+    TEST_IDT.load();
+
+    let mut mapper = unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    unsafe { memory::frame::init(&boot_info.memory_map) };
+    let mut frame_allocator = GlobalFrameAllocator;
+    rust_os::init_stacks(&mut mapper, &mut frame_allocator)
+        .expect("stack initialization failed");
+
+    // Map the user code and stack pages
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    map_page(&mut mapper, &mut frame_allocator, USER_CODE, user | PageTableFlags::WRITABLE);
+    map_page(&mut mapper, &mut frame_allocator, USER_STACK_TOP - 4096, user | PageTableFlags::WRITABLE);
+    unsafe { (USER_CODE as *mut u8).write_volatile(HLT) };
+
+    unsafe { rust_os::user::enter_user_mode(VirtAddr::new(USER_CODE), VirtAddr::new(USER_STACK_TOP)) };
+}
+
+fn map_page(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    address: u64,
+    flags: PageTableFlags,
+) {
+    let page = Page::containing_address(VirtAddr::new(address));
+    let frame = frame_allocator.allocate_frame().expect("out of frames");
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator).expect("mapping failed").flush() };
+}
+
+extern "x86-interrupt" fn test_general_protection_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp) };
+
+    let privilege_stack = gdt::privilege_stack().as_u64();
+    let from_user = stack_frame.code_segment == u64::from(gdt::selectors().user_code_selector.0);
+    let on_privilege_stack = rsp < privilege_stack && rsp >= privilege_stack - 4096;
+    if from_user && stack_frame.instruction_pointer.as_u64() == USER_CODE && on_privilege_stack {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        fail("general protection fault not raised by the user code on the privilege stack");
+    }
+    rust_os::idle_loop();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(_stack_frame: InterruptStackFrame, _error_code: PageFaultErrorCode) {
+    fail("page fault");
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(_stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    fail("double fault");
+}
+
+fn fail(reason: &str) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", reason);
+    exit_qemu(QemuExitCode::Failed);
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,173 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator::{self, HEAP_START};
+use rust_os::memory::{self, frame::{self, GlobalFrameAllocator}, stack::STACK_REGION_START};
+use rust_os::memory::vma::{self, Backing, VmaError, VmaManager, KERNEL_VMAS};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+/// Page table for the tests to map areas with
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { frame::init(&boot_info.memory_map) };
+    allocator::heap_init(&mut mapper, &mut GlobalFrameAllocator)
+        .expect("heap initialization failed");
+    vma::init();
+    *MAPPER.lock() = Some(mapper);
+
+    test_main();
+    KERNEL_VMAS.lock().print_layout();
+    rust_os::idle_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn page_flags(addr: VirtAddr) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    memory::walk_page_tables(addr, |entry| flags = entry.flags);
+    flags
+}
+
+#[test_case]
+fn boot_areas_are_recorded() {
+    let vmas = KERNEL_VMAS.lock();
+    let heap = vmas.find(VirtAddr::new(HEAP_START as u64 + 8)).expect("heap is not recorded");
+    assert_eq!(heap.name, "heap");
+    assert_eq!(heap.backing, Backing::Anonymous);
+    assert!(vmas.find(memory::phys_to_virt(PhysAddr::new(0x1000))).is_some());
+    assert!(vmas.find(VirtAddr::new(0x1000)).is_none());
+}
+
+#[test_case]
+fn boot_areas_are_pinned() {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut vmas = KERNEL_VMAS.lock();
+    let heap = VirtAddr::new(HEAP_START as u64);
+    let window = vmas.find(memory::phys_to_virt(PhysAddr::new(0x1000))).unwrap().start;
+    let stacks = VirtAddr::new(STACK_REGION_START);
+    for start in [heap, window, stacks] {
+        assert!(vmas.find(start).unwrap().pinned);
+        assert!(matches!(vmas.unmap(start, mapper, &mut GlobalFrameAllocator), Err(VmaError::Pinned)));
+        assert!(matches!(vmas.protect(start, PageTableFlags::PRESENT, mapper), Err(VmaError::Pinned)));
+    }
+    assert!(matches!(vmas.map(stacks, Backing::Anonymous, PageTableFlags::PRESENT, mapper, &mut GlobalFrameAllocator),
+        Err(VmaError::Pinned)));
+    assert!(page_flags(heap).contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn unmapping_a_reservation_leaves_its_pages_alone() {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut vmas = KERNEL_VMAS.lock();
+    let start = vmas.reserve("test reservation", 0x1000).expect("reserving failed");
+
+    // A page mapped without the manager doesn't belong to the reservation
+    let frame: PhysFrame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    let page = Page::<Size4KiB>::containing_address(start);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) }.expect("mapping failed").flush();
+    vmas.unmap(start, mapper, &mut GlobalFrameAllocator).expect("unmapping failed");
+    assert!(vmas.find(start).is_none());
+    assert!(memory::is_mapped(start));
+
+    mapper.unmap(page).expect("unmapping failed").1.flush();
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn overlapping_reservations_are_rejected() {
+    let base = VirtAddr::new(0x_2000_0000_0000);
+    let mut vmas = VmaManager::new(base, 0x10_0000);
+    vmas.reserve_at("a", base + 0x4000u64, 0x4000).unwrap();
+    assert!(matches!(vmas.reserve_at("b", base + 0x6000u64, 0x4000), Err(VmaError::Overlap)));
+    assert!(matches!(vmas.reserve_at("c", base + 0x2000u64, 0x4000), Err(VmaError::Overlap)));
+    assert!(matches!(vmas.reserve_at("d", base + 0x2000u64, 0x8000), Err(VmaError::Overlap)));
+    assert!(matches!(vmas.reserve_at("e", base + 0x3000u64, 0x800), Err(VmaError::Unaligned)));
+    vmas.reserve_at("f", base + 0x8000u64, 0x1000).unwrap();
+
+    // Dynamic reservations fit into gaps, with a guard page below them
+    assert_eq!(vmas.reserve("g", 0x2000).unwrap(), base + 0x1000u64);
+    assert_eq!(vmas.reserve("h", 0x1000).unwrap(), base + 0xa000u64);
+    assert!(matches!(vmas.reserve("i", 0x10_0000), Err(VmaError::OutOfSpace)));
+    assert_eq!(vmas.iter().count(), 4);
+}
+
+#[test_case]
+fn reservations_past_the_address_space_are_rejected() {
+    let base = VirtAddr::new(0x_7fff_ffe0_0000);
+    let mut vmas = VmaManager::new(base, 0x10_0000);
+    assert!(matches!(vmas.reserve("a", 1 << 47), Err(VmaError::OutOfSpace)));
+    assert!(matches!(vmas.reserve_at("b", base + 0x10_0000u64, 0x20_0000), Err(VmaError::OutOfSpace)));
+    assert!(matches!(vmas.reserve_at("c", VirtAddr::new(0xffff_ffff_ffff_f000), 0x2000), Err(VmaError::OutOfSpace)));
+    assert!(matches!(KERNEL_VMAS.lock().reserve("d", 1 << 47), Err(VmaError::OutOfSpace)));
+    assert_eq!(vmas.iter().count(), 0);
+}
+
+#[test_case]
+fn map_protect_unmap_anonymous() {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut vmas = KERNEL_VMAS.lock();
+    let start = vmas.reserve("test buffer", 0x3000).expect("reserving failed");
+    assert!(!memory::is_mapped(start));
+
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    vmas.map(start, Backing::Anonymous, writable, mapper, &mut GlobalFrameAllocator).expect("mapping failed");
+    assert!(matches!(vmas.map(start, Backing::Anonymous, writable, mapper, &mut GlobalFrameAllocator),
+        Err(VmaError::AlreadyMapped)));
+    let last = start + 0x2ff8u64;
+    assert_eq!(unsafe { last.as_ptr::<u64>().read_volatile() }, 0);
+    unsafe { last.as_mut_ptr::<u64>().write_volatile(7) };
+
+    vmas.protect(start, PageTableFlags::PRESENT, mapper).expect("protecting failed");
+    assert!(!page_flags(last).contains(PageTableFlags::WRITABLE));
+    assert_eq!(vmas.find(last).unwrap().flags, PageTableFlags::PRESENT);
+
+    let free = frame::with_frame_allocator(|allocator| allocator.free_frames());
+    vmas.unmap(start, mapper, &mut GlobalFrameAllocator).expect("unmapping failed");
+    assert!(!memory::is_mapped(start));
+    assert!(vmas.find(start).is_none());
+    assert_eq!(frame::with_frame_allocator(|allocator| allocator.free_frames()), free + 3);
+}
+
+#[test_case]
+fn map_physical_window() {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut vmas = KERNEL_VMAS.lock();
+    let start = vmas.reserve("test window", 0x4000).expect("reserving failed");
+
+    let phys = PhysAddr::new(0x10_0000);
+    vmas.map(start, Backing::Physical(phys), PageTableFlags::PRESENT, mapper, &mut GlobalFrameAllocator)
+        .expect("mapping failed");
+    let through_window = unsafe { (start + 0x1010u64).as_ptr::<u64>().read_volatile() };
+    let direct = unsafe { memory::phys_to_virt(phys + 0x1010u64).as_ptr::<u64>().read_volatile() };
+    assert_eq!(through_window, direct);
+
+    // Physical memory isn't freed on unmap
+    let free = frame::with_frame_allocator(|allocator| allocator.free_frames());
+    vmas.unmap(start, mapper, &mut GlobalFrameAllocator).expect("unmapping failed");
+    assert_eq!(frame::with_frame_allocator(|allocator| allocator.free_frames()), free);
+}
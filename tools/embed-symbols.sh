@@ -0,0 +1,14 @@
+#!/bin/sh
+# Builds the kernel twice: once to get its symbols, then again with the symbol table embedded, so panics and
+# exceptions print function names in their backtraces. Extra arguments are passed to both cargo invocations.
+set -e
+
+cd "$(dirname "$0")/.."
+target_dir=target/x86_64-rust_os/debug
+for arg in "$@"; do
+    [ "$arg" = "--release" ] && target_dir=target/x86_64-rust_os/release
+done
+
+cargo build "$@"
+nm --defined-only --demangle --numeric-sort "$target_dir/rust-os" > "$target_dir/ksyms.txt"
+KERNEL_SYMBOLS="$PWD/$target_dir/ksyms.txt" cargo build "$@"